@@ -1,53 +1,441 @@
 use std::{
-    fs::OpenOptions,
-    io::{Read, Write},
-    path::PathBuf,
+    cell::RefCell,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
 };
 
+use crate::{
+    rope::Rope,
+    syntax::{language_for, Highlighter, TokenKind},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+    // Every line break is a plain '\n' in memory like for the other styles, so columns
+    // and cursor movement never see a '\r'. The ending of each line is recorded
+    // next to the text and written back on save.
+    Mixed,
+}
+
+impl fmt::Display for LineEnding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineEnding::Lf => write!(f, "LF"),
+            LineEnding::Crlf => write!(f, "CRLF"),
+            LineEnding::Mixed => write!(f, "Mixed"),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Buffer {
     pub name: String,
-    pub buf: String,
+    pub line_ending: LineEnding,
+    pub has_bom: bool,
+    pub has_final_newline: bool,
+    text: Rope,
+    // Per line, whether it ended with "\r\n". Only kept for mixed line endings.
+    crlf_lines: Vec<bool>,
+    // Updated lazily while drawing, which only has shared access to the buffer.
+    highlighter: RefCell<Highlighter>,
 }
 
 impl Buffer {
     pub fn new(bufname: &PathBuf) -> Self {
         Buffer {
             name: bufname.display().to_string(),
-            buf: String::new(),
-        }
-    }
-    pub fn write_to_file(&self) {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.name)
-            .expect("Error occured while opening or creating file!");
-        file.write_all(&self.buf.as_bytes())
-            .expect("Error occured while writing to file!");
-    }
-    pub fn read_to_buffer(&mut self, p: &PathBuf) {
-        let mut buf: String = String::new();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(p)
-            .expect("Error occured while opening or creating file!");
-        match file.read_to_string(&mut buf) {
-            Ok(read_bytes) => {
-                println!("{read_bytes} Bytes read from file!");
-                if read_bytes == 0 {
-                    self.buf.push('\n');
+            line_ending: LineEnding::Lf,
+            has_bom: false,
+            has_final_newline: true,
+            text: Rope::default(),
+            crlf_lines: Vec::new(),
+            highlighter: RefCell::new(Highlighter::new(language_for(bufname))),
+        }
+    }
+    // Writes to a temporary file next to the target and renames it over the original,
+    // so a crash or a full disk mid-write never leaves a truncated document behind.
+    pub fn write_to_file(&self, backup: bool) -> io::Result<()> {
+        let mut path = PathBuf::from(&self.name);
+        if path.is_symlink() {
+            path = fs::canonicalize(&path)?;
+        }
+        let dir = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name!"))?
+            .to_string_lossy()
+            .into_owned();
+        let tmp_path = dir.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+        let orig_meta = fs::metadata(&path).ok();
+
+        let res = (|| {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)?;
+            file.write_all(self.to_file_contents().as_bytes())?;
+            if let Some(meta) = &orig_meta {
+                file.set_permissions(meta.permissions())?;
+            }
+            file.sync_all()?;
+            if backup && orig_meta.is_some() {
+                fs::copy(&path, dir.join(format!("{}~", file_name)))?;
+            }
+            fs::rename(&tmp_path, &path)
+        })();
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+            return res;
+        }
+        #[cfg(unix)]
+        if let Ok(d) = fs::File::open(&dir) {
+            let _ = d.sync_all();
+        }
+        Ok(())
+    }
+    // Swap file next to the document first, then one under the XDG state dir for
+    // documents living in directories we cannot write to. The name must not clash
+    // with vim's ".name.swp", those are binary and still in use by vim.
+    fn swap_candidates(&self) -> Vec<PathBuf> {
+        let path = PathBuf::from(&self.name);
+        let mut res = Vec::new();
+        if let Some(file_name) = path.file_name() {
+            let swap_name = format!(".{}.rustyed.swp", file_name.to_string_lossy());
+            res.push(match path.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p.join(swap_name),
+                _ => PathBuf::from(swap_name),
+            });
+        }
+        let state_dir = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")));
+        if let Some(dir) = state_dir {
+            let abs = fs::canonicalize(&path).unwrap_or(path);
+            let flat = abs.display().to_string().replace(['/', '\\', ':'], "%");
+            res.push(dir.join("rustyed").join(format!("{flat}.swp")));
+        }
+        res
+    }
+
+    pub fn write_swap(&self) -> io::Result<PathBuf> {
+        let contents = self.to_file_contents();
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No swap file location!");
+        for swap in self.swap_candidates() {
+            if let Some(dir) = swap.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            match fs::write(&swap, contents.as_bytes()) {
+                Ok(()) => return Ok(swap),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    pub fn remove_swap(&self) {
+        for swap in self.swap_candidates() {
+            let _ = fs::remove_file(swap);
+        }
+    }
+
+    // A swap file is only worth recovering when it is newer than the document on disk.
+    pub fn find_swap(&self) -> Option<PathBuf> {
+        let doc_modified = fs::metadata(&self.name).and_then(|m| m.modified()).ok();
+        self.swap_candidates().into_iter().find(|swap| {
+            match (fs::metadata(swap).and_then(|m| m.modified()), doc_modified) {
+                (Ok(swap_modified), Some(doc_modified)) => swap_modified > doc_modified,
+                (Ok(_), None) => true,
+                (Err(_), _) => false,
+            }
+        })
+    }
+
+    fn to_file_contents(&self) -> String {
+        let mut text = self.text.to_string();
+        if !self.has_final_newline {
+            text.pop();
+        }
+        match self.line_ending {
+            LineEnding::Lf => (),
+            LineEnding::Crlf => text = text.replace('\n', "\r\n"),
+            LineEnding::Mixed => {
+                let mut res = String::with_capacity(text.len() + self.crlf_lines.len());
+                let crlf = self.crlf_lines.iter().chain(std::iter::repeat(&false));
+                for (line, &is_crlf) in text.split_inclusive('\n').zip(crlf) {
+                    match line.strip_suffix('\n') {
+                        Some(l) if is_crlf => {
+                            res.push_str(l);
+                            res.push_str("\r\n");
+                        }
+                        _ => res.push_str(line),
+                    }
                 }
+                text = res;
             }
-            Err(e) => {
-                eprintln!("Error occured while opening and reading file with error code: {e}")
+        }
+        if self.has_bom {
+            text.insert(0, '\u{FEFF}');
+        }
+        text
+    }
+    #[cfg(test)]
+    pub fn from_text(text: &str) -> Buffer {
+        let mut buffer = Buffer::default();
+        buffer.load_text(text.to_owned());
+        buffer
+    }
+
+    // Loads a swap file's text, keeping the document name.
+    pub fn read_swap(&mut self, swap: &Path) -> io::Result<()> {
+        let buf = fs::read_to_string(swap)?;
+        self.load_text(buf);
+        Ok(())
+    }
+
+    // Loads the file's text; a missing file opens as an empty buffer and is created on save.
+    pub fn read_to_buffer(&mut self, p: &Path) -> io::Result<()> {
+        let buf = match fs::read_to_string(p) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        self.load_text(buf);
+        Ok(())
+    }
+
+    fn load_text(&mut self, mut buf: String) {
+        self.has_bom = buf.starts_with('\u{FEFF}');
+        if self.has_bom {
+            buf.remove(0);
+        }
+        let crlf_count = buf.matches("\r\n").count();
+        self.line_ending = if crlf_count == 0 {
+            LineEnding::Lf
+        } else if crlf_count == buf.matches('\n').count() {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Mixed
+        };
+        self.crlf_lines = if self.line_ending == LineEnding::Mixed {
+            buf.split_inclusive('\n')
+                .map(|l| l.ends_with("\r\n"))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if self.line_ending != LineEnding::Lf {
+            buf = buf.replace("\r\n", "\n");
+        }
+        // The editor expects every line to end with '\n'; a missing one is only added in memory.
+        self.has_final_newline = buf.ends_with('\n');
+        if !self.has_final_newline {
+            buf.push('\n');
+        }
+        self.text = Rope::from(buf.as_str());
+        self.highlighter.get_mut().reset(self.text.len_newlines());
+    }
+
+    pub fn crlf_lines(&self) -> Vec<bool> {
+        self.crlf_lines.clone()
+    }
+
+    // Switches the style of every line, crlf_lines only matters for a mixed one.
+    pub fn set_line_ending(&mut self, line_ending: LineEnding, crlf_lines: Vec<bool>) {
+        self.line_ending = line_ending;
+        self.crlf_lines = if line_ending == LineEnding::Mixed {
+            crlf_lines
+        } else {
+            Vec::new()
+        };
+    }
+
+    // Keeps the endings of a mixed file in step with an edit that turned lines
+    // line..=line+removed into line..=line+added. The lines left take the ending of
+    // the last line the edit touched.
+    fn edit_line_endings(&mut self, line: usize, removed: usize, added: usize) {
+        if self.line_ending != LineEnding::Mixed {
+            return;
+        }
+        let len = self.crlf_lines.len();
+        let last = std::cmp::min(line + removed, len.saturating_sub(1));
+        if line < len {
+            let is_crlf = self.crlf_lines[last];
+            self.crlf_lines
+                .splice(line..=last, std::iter::repeat_n(is_crlf, added + 1));
+        }
+        self.crlf_lines.resize(self.text.len_newlines(), false);
+    }
+
+    // Calls f with the char offset of every occurrence of needle, overlapping ones
+    // included, until it returns false. Runs KMP over the rope's chunks so the text
+    // is never copied.
+    pub fn for_each_occurrence(&self, needle: &str, f: &mut dyn FnMut(usize) -> bool) {
+        let pat: Vec<char> = needle.chars().collect();
+        if pat.is_empty() {
+            return;
+        }
+        let mut fail = vec![0; pat.len()];
+        let mut k = 0;
+        for i in 1..pat.len() {
+            while k > 0 && pat[i] != pat[k] {
+                k = fail[k - 1];
+            }
+            if pat[i] == pat[k] {
+                k += 1;
             }
+            fail[i] = k;
         }
-        buf = buf.replace("\r\n", "\n");
-        buf.push('\n');
-        self.buf = buf;
+        let mut k = 0;
+        let mut idx = 0;
+        for chunk in self.text.chunks() {
+            for c in chunk.chars() {
+                while k > 0 && c != pat[k] {
+                    k = fail[k - 1];
+                }
+                if c == pat[k] {
+                    k += 1;
+                }
+                idx += 1;
+                if k == pat.len() {
+                    if !f(idx - pat.len()) {
+                        return;
+                    }
+                    k = fail[k - 1];
+                }
+            }
+        }
+    }
+
+    // Shares the text with the buffer, later edits copy only the nodes they touch.
+    pub fn snapshot(&self) -> Rope {
+        self.text.clone()
+    }
+
+    pub fn len_chars(&self) -> usize {
+        self.text.len_chars()
+    }
+
+    // Every line in the buffer is terminated by '\n', so this is also the newline count.
+    pub fn line_count(&self) -> usize {
+        self.text.len_newlines()
+    }
+
+    pub fn char_at(&self, idx: usize) -> char {
+        self.text
+            .char(idx)
+            .expect("Buffer char index out of bounds!")
+    }
+
+    pub fn insert(&mut self, idx: usize, s: &str) {
+        let line = self.text.char_to_line(idx);
+        self.text.insert(idx, s);
+        let added = s.matches('\n').count();
+        self.edit_line_endings(line, 0, added);
+        self.highlighter.get_mut().edit(line, 0, added);
+    }
+
+    pub fn insert_char(&mut self, idx: usize, c: char) {
+        self.insert(idx, c.encode_utf8(&mut [0u8; 4]));
+    }
+
+    pub fn remove(&mut self, range: Range<usize>) {
+        let line = self.text.char_to_line(range.start);
+        let removed = self.text.char_to_line(range.end) - line;
+        self.text.remove(range);
+        self.edit_line_endings(line, removed, 0);
+        self.highlighter.get_mut().edit(line, removed, 0);
+    }
+
+    pub fn language_name(&self) -> Option<&'static str> {
+        self.highlighter.borrow().language().map(|l| l.name)
+    }
+
+    // Token kind of every char of the line, None for plain text documents.
+    pub fn line_tokens(&self, line: usize) -> Option<Vec<TokenKind>> {
+        self.highlighter
+            .borrow_mut()
+            .line_kinds(line, |l| {
+                let mut chars: Vec<char> = self.line(l).chars().collect();
+                if chars.last() == Some(&'\n') {
+                    chars.pop();
+                }
+                chars
+            })
+            .map(|k| k.to_vec())
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> String {
+        self.text.slice(range)
+    }
+
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.text.line_to_char(line)
+    }
+
+    pub fn char_to_line(&self, idx: usize) -> usize {
+        self.text.char_to_line(idx)
+    }
+
+    pub fn offset_to_line_col(&self, idx: usize) -> (usize, usize) {
+        let line = self.char_to_line(idx);
+        (line, idx - self.line_to_char(line))
+    }
+
+    // Line contents including the terminating '\n'.
+    pub fn line(&self, line: usize) -> String {
+        self.slice(self.line_to_char(line)..self.line_to_char(line + 1))
+    }
+
+    // Line length in chars, not counting the terminating '\n'.
+    pub fn line_len(&self, line: usize) -> usize {
+        (self.line_to_char(line + 1) - self.line_to_char(line)).saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mixed_line_endings_are_kept_out_of_the_text() {
+        let buffer = Buffer::from_text("a\r\nbc\nd\r\n");
+        assert_eq!(buffer.line_ending, LineEnding::Mixed);
+        assert_eq!(buffer.line_len(0), 1);
+        assert_eq!(buffer.slice(0..buffer.len_chars()), "a\nbc\nd\n");
+        assert_eq!(buffer.to_file_contents(), "a\r\nbc\nd\r\n");
+    }
+
+    #[test]
+    fn mixed_line_endings_follow_edits() {
+        let mut buffer = Buffer::from_text("a\r\nb\nc");
+        // Splitting the first line, the new line ends like the one it came from.
+        buffer.insert(1, "x\ny");
+        assert_eq!(buffer.to_file_contents(), "ax\r\ny\r\nb\nc");
+        // Joining lines keeps the ending of the last one.
+        buffer.remove(2..5);
+        assert_eq!(buffer.to_file_contents(), "axb\nc");
+        buffer.remove(0..buffer.len_chars() - 1);
+        assert_eq!(buffer.to_file_contents(), "");
+    }
+
+    #[test]
+    fn reading_a_missing_file_gives_an_empty_buffer() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join(format!("rustyed-missing-{}", std::process::id()));
+        let mut buffer = Buffer::new(&missing);
+        assert!(buffer.read_to_buffer(&missing).is_ok());
+        assert_eq!(buffer.to_file_contents(), "");
+        assert!(!missing.exists());
+        // Anything else that can't be read is an error, not a panic.
+        assert!(buffer.read_to_buffer(&dir).is_err());
     }
 }
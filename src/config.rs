@@ -15,6 +15,22 @@ pub struct Config {
     pub cursor_line: Option<String>,
     pub tab_width: Option<String>,
     pub eof_indicator: Option<String>,
+    pub backup: Option<String>,
+    pub swap_interval: Option<String>,
+    pub search_col: Option<String>,
+    pub search_current_col: Option<String>,
+    pub scrollbar_col: Option<String>,
+    pub line_numbers: Option<String>,
+    pub relative_line_numbers: Option<String>,
+    pub gutter_col: Option<String>,
+    pub gutter_font_col: Option<String>,
+    pub gutter_current_col: Option<String>,
+    pub syntax_highlight: Option<String>,
+    pub theme: Option<String>,
+    pub soft_wrap: Option<String>,
+    pub horizontal_margin: Option<String>,
+    pub scroll_lines: Option<String>,
+    pub smooth_scroll: Option<String>,
 }
 
 impl Default for Config {
@@ -29,6 +45,22 @@ impl Default for Config {
             cursor_line: None,
             tab_width: None,
             eof_indicator: None,
+            backup: None,
+            swap_interval: None,
+            search_col: None,
+            search_current_col: None,
+            scrollbar_col: None,
+            line_numbers: None,
+            relative_line_numbers: None,
+            gutter_col: None,
+            gutter_font_col: None,
+            gutter_current_col: None,
+            syntax_highlight: None,
+            theme: None,
+            soft_wrap: None,
+            horizontal_margin: None,
+            scroll_lines: None,
+            smooth_scroll: None,
         }
     }
 }
@@ -86,6 +118,22 @@ pub fn parse_config(conf_path: &Path) -> Result<Config, Box<dyn Error>> {
     config.cursor_line = pairs.get("cursor_line").cloned().take();
     config.tab_width = pairs.get("tab_width").cloned().take();
     config.eof_indicator = pairs.get("eof_indicator").cloned().take();
+    config.backup = pairs.get("backup").cloned().take();
+    config.swap_interval = pairs.get("swap_interval").cloned().take();
+    config.search_col = pairs.get("search_col").cloned().take();
+    config.search_current_col = pairs.get("search_current_col").cloned().take();
+    config.scrollbar_col = pairs.get("scrollbar_col").cloned().take();
+    config.line_numbers = pairs.get("line_numbers").cloned().take();
+    config.relative_line_numbers = pairs.get("relative_line_numbers").cloned().take();
+    config.gutter_col = pairs.get("gutter_col").cloned().take();
+    config.gutter_font_col = pairs.get("gutter_font_col").cloned().take();
+    config.gutter_current_col = pairs.get("gutter_current_col").cloned().take();
+    config.syntax_highlight = pairs.get("syntax_highlight").cloned().take();
+    config.theme = pairs.get("theme").cloned().take();
+    config.soft_wrap = pairs.get("soft_wrap").cloned().take();
+    config.horizontal_margin = pairs.get("horizontal_margin").cloned().take();
+    config.scroll_lines = pairs.get("scroll_lines").cloned().take();
+    config.smooth_scroll = pairs.get("smooth_scroll").cloned().take();
 
     Ok(config)
 }
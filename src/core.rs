@@ -1,35 +1,81 @@
 use crate::{
     buffer::Buffer,
     config::*,
-    render::{from_str_to_cells, Cell},
+    editor::{prompt_recover_swap, remember_recent_file, Change},
+    finder::FileIndexJob,
+    pane::{Layout, Pane},
+    render::{from_str_to_cells, text_area, Cell},
+    search::{FileMatch, FileSearchJob, SearchJob},
+    theme::{load_theme, Theme},
 };
 use copypasta::ClipboardContext;
 use macroquad::prelude::*;
+use regex::Regex;
 use std::{
     path::{Path, PathBuf},
     time::Instant,
 };
+use undo::Record;
 
 #[derive(PartialEq)]
 pub enum Modes {
     GoToLine,
     FindCaseSensitive,
     FindCaseInSensitive,
+    ReplaceCaseSensitive,
+    ReplaceCaseInSensitive,
     Edit,
     ModifiedPrompt,
     ShowHelp,
+    RecoverPrompt,
+    ShowSwapDiff,
+    BufferList,
+    FindInFiles,
+    QuickOpen,
+    ThemeList,
 }
 
-pub type SearchResults = Vec<(usize, (usize, usize))>;
+// (index, start (col, line), end (col, line)), the end position is exclusive.
+pub type SearchResults = Vec<(usize, (usize, usize), (usize, usize))>;
+
+pub type SelectionRange = ((usize, (usize, usize)), (usize, (usize, usize)));
+
+// (line, column) of where a rectangular selection started and of its moving corner.
+// Columns may lie past the end of a line.
+pub type BlockSelection = ((usize, usize), (usize, usize));
+
+// A cursor besides the main one. Kept as buffer offsets so scrolling does not move
+// it, its selection is inclusive on both ends like SelectionRange.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExtraCursor {
+    pub off: usize,
+    pub selection: Option<(usize, usize)>,
+}
+
+// Per-document state of a buffer that is not the active one. The active document
+// lives directly in Context, its slot in Context::buffers is left empty.
+#[derive(Default)]
+pub struct BufferState {
+    pub buffer: Buffer,
+    pub active_buf: PathBuf,
+    pub curr_cursor_pos: (usize, usize),
+    pub vert_first_line: usize,
+    pub horiz_offset: f32,
+    pub selection_range: Option<SelectionRange>,
+    pub is_file_changed: bool,
+    pub is_swap_stale: bool,
+    pub record: Record<Change>,
+}
 
 pub struct Context {
     pub mouse_pos: (f32, f32),
     pub curr_cursor_pos: (usize, usize),
-    pub bg_color: Color,
     pub font: Font,
-    pub font_color: Color,
-    pub cursor_col: Color,
-    pub selection_col: Color,
+    pub theme: Theme,
+    pub is_line_numbers: bool,
+    pub is_relative_line_numbers: bool,
+    pub is_syntax_highlight: bool,
+    pub is_soft_wrap: bool,
     pub font_size: u16,
     pub buffer: Buffer,
     pub cells: Vec<Cell>,
@@ -37,19 +83,75 @@ pub struct Context {
     pub is_exit: bool,
     pub is_cursorline: bool,
     pub vert_cell_count: (usize, usize),
+    // Pixels the text is scrolled to the left, always 0 with soft wrap.
+    pub horiz_offset: f32,
+    pub horiz_margin: usize,
+    pub scroll_lines: usize,
+    pub is_smooth_scroll: bool,
+    // Pixels of the first line scrolled out at the top, only with smooth scrolling.
+    pub scroll_px: f32,
+    // Wheel scrolling still to be done, in pixels.
+    pub scroll_pending: f32,
     pub mode: Modes,
     pub prompt_input: String,
     pub search_res: SearchResults,
-    pub is_search_changed: bool,
     pub last_searched_idx: usize,
     pub last_searched_term: String,
+    pub is_regex_search: bool,
+    pub is_whole_word_search: bool,
+    pub is_search_in_selection: bool,
+    pub search_selection: Option<SelectionRange>,
+    pub search_scope: Option<(usize, usize)>,
+    pub search_error: Option<String>,
+    pub search_job: Option<SearchJob>,
+    // Compiled once per pattern, on the first replace that needs it.
+    pub search_regex: Option<Regex>,
+    pub search_origin: ((usize, usize), usize),
+    pub is_search_jump_pending: bool,
+    pub replace_input: String,
+    pub is_replace_input_active: bool,
+    pub file_search_root: PathBuf,
+    pub file_search_term: String,
+    pub is_file_search_case_sensitive: bool,
+    pub file_search_res: Vec<FileMatch>,
+    pub file_search_error: Option<String>,
+    pub file_search_job: Option<FileSearchJob>,
+    pub quick_open_root: PathBuf,
+    pub quick_open_files: Vec<PathBuf>,
+    // (file index, score) best first.
+    pub quick_open_res: Vec<(usize, i32)>,
+    pub quick_open_job: Option<FileIndexJob>,
+    pub recent_files: Vec<PathBuf>,
     pub is_font_monospaced: Option<f32>,
     pub is_file_changed: bool,
     pub tab_width: u8,
     pub eof_indicator: bool,
-    pub selection_range: Option<((usize, (usize, usize)), (usize, (usize, usize)))>,
+    pub backup: bool,
+    pub status_msg: Option<String>,
+    pub swap_interval: u64,
+    pub last_swap: Instant,
+    pub is_swap_stale: bool,
+    pub overlay_lines: Vec<String>,
+    pub overlay_scroll: usize,
+    pub overlay_selected: usize,
+    pub buffers: Vec<BufferState>,
+    pub active_idx: usize,
+    pub panes: Vec<Pane>,
+    pub active_pane: usize,
+    pub layout: Layout,
+    pub viewport: Rect,
+    pub selection_range: Option<SelectionRange>,
+    pub extra_cursors: Vec<ExtraCursor>,
+    pub block_selection: Option<BlockSelection>,
+    // Text last copied from a block, pasting exactly this text pastes it as a column.
+    pub block_clip: Option<String>,
     pub clipboard: ClipboardContext,
     pub timer: Option<Instant>,
+    // Time and buffer offset of the previous click, to count double and triple clicks.
+    pub last_click: Option<(Instant, usize)>,
+    pub click_count: usize,
+    // Word or line picked by a double or triple click, dragging extends from it.
+    pub click_anchor: Option<(usize, usize)>,
 }
 
 impl Default for Context {
@@ -57,11 +159,12 @@ impl Default for Context {
         Context {
             mouse_pos: mouse_position(),
             curr_cursor_pos: (0, 0),
-            bg_color: color_u8!(0, 0, 0, 255),
             font: Default::default(),
-            font_color: color_u8!(255, 255, 255, 255),
-            cursor_col: color_u8!(200, 200, 200, 255),
-            selection_col: color_u8!(55, 95, 25, 5),
+            theme: Theme::default(),
+            is_line_numbers: true,
+            is_relative_line_numbers: false,
+            is_syntax_highlight: true,
+            is_soft_wrap: false,
             font_size: 10,
             buffer: Default::default(),
             is_cursorline: false,
@@ -69,19 +172,67 @@ impl Default for Context {
             active_buf: Default::default(),
             is_exit: false,
             vert_cell_count: (0, 10),
+            horiz_offset: 0f32,
+            horiz_margin: 4,
+            scroll_lines: 3,
+            is_smooth_scroll: false,
+            scroll_px: 0f32,
+            scroll_pending: 0f32,
             mode: Modes::Edit,
             prompt_input: String::new(),
             search_res: Default::default(),
-            is_search_changed: false,
             last_searched_idx: Default::default(),
             last_searched_term: String::new(),
+            is_regex_search: false,
+            is_whole_word_search: false,
+            is_search_in_selection: false,
+            search_selection: None,
+            search_scope: None,
+            search_error: None,
+            search_job: None,
+            search_regex: None,
+            search_origin: ((0, 0), 0),
+            is_search_jump_pending: false,
+            replace_input: String::new(),
+            is_replace_input_active: false,
+            file_search_root: PathBuf::new(),
+            file_search_term: String::new(),
+            is_file_search_case_sensitive: false,
+            file_search_res: Vec::new(),
+            file_search_error: None,
+            file_search_job: None,
+            quick_open_root: PathBuf::new(),
+            quick_open_files: Vec::new(),
+            quick_open_res: Vec::new(),
+            quick_open_job: None,
+            recent_files: Vec::new(),
             is_font_monospaced: None,
             is_file_changed: false,
             tab_width: 2,
             eof_indicator: false,
+            backup: false,
+            status_msg: None,
+            swap_interval: 4,
+            last_swap: Instant::now(),
+            is_swap_stale: false,
+            overlay_lines: Vec::new(),
+            overlay_scroll: 0,
+            overlay_selected: 0,
+            buffers: vec![BufferState::default()],
+            active_idx: 0,
+            panes: vec![Pane::default()],
+            active_pane: 0,
+            layout: Layout::default(),
+            viewport: Rect::default(),
             selection_range: None,
+            extra_cursors: Vec::new(),
+            block_selection: None,
+            block_clip: None,
             clipboard: ClipboardContext::new().expect("Failed when creating clipboard context!"),
             timer: None,
+            last_click: None,
+            click_count: 0,
+            click_anchor: None,
         }
     }
 }
@@ -127,17 +278,81 @@ pub async fn init(ctx: &mut Context, conf_path: &Path, file: &PathBuf) {
         }
         ctx.font = fontt;
     }
+    if let Some(name) = conf.theme {
+        match load_theme(&name) {
+            Ok(theme) => ctx.theme = theme,
+            Err(e) => {
+                println!("{e} Fallback to classic theme!");
+                ctx.status_msg = Some(e);
+            }
+        }
+    }
+    // Colors set directly in the config override the theme.
     if let Some(bgcol) = conf.bg_col {
-        ctx.bg_color = color_ascii_to_4u8(&bgcol);
+        ctx.theme.bg = color_ascii_to_4u8(&bgcol);
     }
     if let Some(foncol) = conf.font_col {
-        ctx.font_color = color_ascii_to_4u8(&foncol);
+        ctx.theme.font = color_ascii_to_4u8(&foncol);
     }
     if let Some(selcol) = conf.select_col {
-        ctx.selection_col = color_ascii_to_4u8(&selcol);
+        ctx.theme.selection = color_ascii_to_4u8(&selcol);
     }
     if let Some(curcol) = conf.cursor_col {
-        ctx.cursor_col = color_ascii_to_4u8(&curcol);
+        ctx.theme.cursor = color_ascii_to_4u8(&curcol);
+    }
+    if let Some(searchcol) = conf.search_col {
+        ctx.theme.search = color_ascii_to_4u8(&searchcol);
+    }
+    if let Some(searchcurcol) = conf.search_current_col {
+        ctx.theme.search_current = color_ascii_to_4u8(&searchcurcol);
+    }
+    if let Some(scrollcol) = conf.scrollbar_col {
+        ctx.theme.scrollbar = color_ascii_to_4u8(&scrollcol);
+    }
+    if let Some(guttercol) = conf.gutter_col {
+        ctx.theme.gutter = color_ascii_to_4u8(&guttercol);
+    }
+    if let Some(gutterfontcol) = conf.gutter_font_col {
+        ctx.theme.gutter_font = color_ascii_to_4u8(&gutterfontcol);
+    }
+    if let Some(guttercurcol) = conf.gutter_current_col {
+        ctx.theme.gutter_current = color_ascii_to_4u8(&guttercurcol);
+    }
+    if let Some(line_nums) = conf.line_numbers {
+        ctx.is_line_numbers = line_nums
+            .parse::<bool>()
+            .expect("Error happend while parsing line_numbers property!");
+    }
+    if let Some(rel_nums) = conf.relative_line_numbers {
+        ctx.is_relative_line_numbers = rel_nums
+            .parse::<bool>()
+            .expect("Error happend while parsing relative_line_numbers property!");
+    }
+    if let Some(syntax) = conf.syntax_highlight {
+        ctx.is_syntax_highlight = syntax
+            .parse::<bool>()
+            .expect("Error happend while parsing syntax_highlight property!");
+    }
+    if let Some(wrap) = conf.soft_wrap {
+        ctx.is_soft_wrap = wrap
+            .parse::<bool>()
+            .expect("Error happend while parsing soft_wrap property!");
+    }
+    if let Some(margin) = conf.horizontal_margin {
+        ctx.horiz_margin = margin
+            .parse::<usize>()
+            .expect("Error happend while parsing horizontal_margin property!");
+    }
+    if let Some(lines) = conf.scroll_lines {
+        ctx.scroll_lines = lines
+            .parse::<usize>()
+            .expect("Error happend while parsing scroll_lines property!")
+            .max(1);
+    }
+    if let Some(smooth) = conf.smooth_scroll {
+        ctx.is_smooth_scroll = smooth
+            .parse::<bool>()
+            .expect("Error happend while parsing smooth_scroll property!");
     }
     if let Some(cur_line) = conf.cursor_line {
         let opt = cur_line
@@ -160,11 +375,32 @@ pub async fn init(ctx: &mut Context, conf_path: &Path, file: &PathBuf) {
             .parse::<bool>()
             .expect("Error happend while parsing eof_indicator property!");
     }
+    if let Some(bckp) = conf.backup {
+        ctx.backup = bckp
+            .parse::<bool>()
+            .expect("Error happend while parsing backup property!");
+    }
+    if let Some(swapint) = conf.swap_interval {
+        ctx.swap_interval = swapint
+            .parse::<u64>()
+            .expect("Error happend while parsing swap_interval property!");
+    }
     ctx.buffer = Buffer::new(file);
-    ctx.buffer.read_to_buffer(file);
+    if let Err(e) = ctx.buffer.read_to_buffer(file) {
+        ctx.status_msg = Some(format!("Error occured while opening file: {e}"));
+    }
     ctx.active_buf = file.to_owned();
-    ctx.vert_cell_count = (0, screen_height() as usize / ctx.font_size as usize + 1);
+    remember_recent_file(ctx, file);
+    ctx.viewport = text_area(ctx);
+    ctx.vert_cell_count = (0, ctx.viewport.h as usize / ctx.font_size as usize + 1);
     ctx.is_font_monospaced = is_font_monospaced(ctx);
 
     from_str_to_cells(ctx);
+
+    if let Some(swap) = ctx.buffer.find_swap() {
+        ctx.mode = Modes::RecoverPrompt;
+        prompt_recover_swap(ctx, &swap).await;
+        ctx.mode = Modes::Edit;
+        from_str_to_cells(ctx);
+    }
 }
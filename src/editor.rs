@@ -1,22 +1,46 @@
-use std::{str::FromStr, time::Instant};
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
 
 use copypasta::{self, ClipboardProvider};
 use macroquad::{
     input,
+    math::vec2,
     prelude::{
         is_key_down, is_key_pressed, is_mouse_button_pressed, is_quit_requested, KeyCode,
         MouseButton,
     },
     window::screen_height,
 };
+use regex::Regex;
 use rfd::FileDialog;
 use undo::Record;
 
 use crate::{
-    core::{Context, Modes, SearchResults},
-    render::{from_cells_to_string, from_str_to_cells, render, Cell},
+    buffer::{Buffer, LineEnding},
+    core::{BlockSelection, BufferState, Context, ExtraCursor, Modes, SelectionRange},
+    finder::{merge_ranked, project_root, rank_files, FileIndexJob, IndexMsg},
+    pane::{Pane, SplitDir},
+    render::{
+        buffer_display_name, column_span, column_width, from_str_to_cells, gutter_width,
+        line_wrap_starts, pane_rects, render, tab_bar_height, tab_layout, wrap_width, Cell,
+    },
+    search::{
+        build_regex, for_each_match, FileMatch, FileSearchJob, FileSearchMsg, Match, Query,
+        SearchJob, SearchMsg,
+    },
+    theme::{load_theme, theme_names},
 };
 
+const MAX_RECENT_FILES: usize = 20;
+
+// Columns scrolled sideways per wheel notch.
+const WHEEL_COLUMNS: f32 = 4f32;
+
+// Longest pause between clicks that still counts as a double or triple click.
+const MULTI_CLICK_MS: u128 = 400;
+
 pub enum Command {
     Exit,
     PageUp,
@@ -28,6 +52,8 @@ pub enum Command {
     GoToLine,
     FindCaseSensitive,
     FindInCaseSensitive,
+    ReplaceCaseSensitive,
+    ReplaceInCaseSensitive,
     Home,
     Help,
     End,
@@ -40,6 +66,7 @@ pub enum Command {
     WordMoveLeft,
     WordMoveRight,
     MouseLeftClick,
+    MouseMiddleClick,
     MouseDown,
     ShiftSelectUp,
     ShiftSelectDown,
@@ -57,11 +84,81 @@ pub enum Command {
     Backspace,
     CharPressed(char),
     DeleteWord,
+    ConvertLineEnding,
+    NextBuffer,
+    PrevBuffer,
+    BufferList,
+    CloseBuffer,
+    SplitVertical,
+    SplitHorizontal,
+    ClosePane,
+    FocusPaneLeft,
+    FocusPaneRight,
+    FocusPaneUp,
+    FocusPaneDown,
+    GrowPane,
+    ShrinkPane,
+    FindInFiles,
+    QuickOpen,
+    SwitchTheme,
+    ToggleSoftWrap,
+    ScrollHorizontal(f32),
+    ScrollVertical(f32),
+    AddNextOccurrence,
+    CursorsOnLines,
+    BlockSelectLeft,
+    BlockSelectRight,
+    BlockSelectUp,
+    BlockSelectDown,
+    Outdent,
 }
 
 pub fn get_command() -> Option<Command> {
+    let (wheel_x, wheel_y) = input::mouse_wheel();
     if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::S) {
         Some(Command::Save)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::V) {
+        Some(Command::SplitVertical)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::S) {
+        Some(Command::SplitHorizontal)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Q) {
+        Some(Command::ClosePane)
+    } else if is_key_down(KeyCode::LeftAlt)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::Left)
+    {
+        Some(Command::BlockSelectLeft)
+    } else if is_key_down(KeyCode::LeftAlt)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::Right)
+    {
+        Some(Command::BlockSelectRight)
+    } else if is_key_down(KeyCode::LeftAlt)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::Up)
+    {
+        Some(Command::BlockSelectUp)
+    } else if is_key_down(KeyCode::LeftAlt)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::Down)
+    {
+        Some(Command::BlockSelectDown)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Left) {
+        Some(Command::FocusPaneLeft)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Right) {
+        Some(Command::FocusPaneRight)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Up) {
+        Some(Command::FocusPaneUp)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Down) {
+        Some(Command::FocusPaneDown)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Equal) {
+        Some(Command::GrowPane)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Minus) {
+        Some(Command::ShrinkPane)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::F) {
+        Some(Command::FindInFiles)
+    } else if is_key_down(KeyCode::LeftAlt) && is_key_pressed(KeyCode::Z) {
+        Some(Command::ToggleSoftWrap)
     } else if is_key_down(KeyCode::LeftShift) && is_key_pressed(KeyCode::Left) {
         Some(Command::ShiftSelectLeft)
     } else if is_key_down(KeyCode::LeftShift) && is_key_pressed(KeyCode::Right) {
@@ -86,16 +183,45 @@ pub fn get_command() -> Option<Command> {
         Some(Command::Redo)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::Left) {
         Some(Command::WordMoveLeft)
+    } else if is_key_down(KeyCode::LeftControl)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::W)
+    {
+        Some(Command::CloseBuffer)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::W) {
         Some(Command::DeleteWord)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::H) {
         Some(Command::Help)
+    } else if is_key_down(KeyCode::LeftControl)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::L)
+    {
+        Some(Command::CursorsOnLines)
+    } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::L) {
+        Some(Command::ConvertLineEnding)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::Right) {
         Some(Command::WordMoveRight)
+    } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::D) {
+        Some(Command::AddNextOccurrence)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::G) {
         Some(Command::GoToLine)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::O) {
         Some(Command::OpenDocument)
+    } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::P) {
+        Some(Command::QuickOpen)
+    } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::B) {
+        Some(Command::BufferList)
+    } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::T) {
+        Some(Command::SwitchTheme)
+    } else if is_key_down(KeyCode::LeftControl)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::Tab)
+    {
+        Some(Command::PrevBuffer)
+    } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::Tab) {
+        Some(Command::NextBuffer)
+    } else if is_key_down(KeyCode::LeftShift) && is_key_pressed(KeyCode::Tab) {
+        Some(Command::Outdent)
     } else if is_key_down(KeyCode::LeftControl)
         && !is_key_down(KeyCode::LeftShift)
         && is_key_pressed(KeyCode::F)
@@ -106,6 +232,16 @@ pub fn get_command() -> Option<Command> {
         && is_key_pressed(KeyCode::F)
     {
         Some(Command::FindInCaseSensitive)
+    } else if is_key_down(KeyCode::LeftControl)
+        && !is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::R)
+    {
+        Some(Command::ReplaceCaseSensitive)
+    } else if is_key_down(KeyCode::LeftControl)
+        && is_key_down(KeyCode::LeftShift)
+        && is_key_pressed(KeyCode::R)
+    {
+        Some(Command::ReplaceInCaseSensitive)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::PageUp) {
         Some(Command::GoTop)
     } else if is_key_down(KeyCode::LeftControl) && is_key_pressed(KeyCode::PageDown) {
@@ -122,8 +258,20 @@ pub fn get_command() -> Option<Command> {
         Some(Command::PageUp)
     } else if is_key_pressed(KeyCode::Enter) {
         Some(Command::Enter)
+    } else if wheel_x.abs() > wheel_y.abs() || (is_key_down(KeyCode::LeftShift) && wheel_y != 0f32)
+    {
+        // Shift turns the wheel sideways, trackpads report horizontal deltas directly.
+        Some(Command::ScrollHorizontal(if wheel_x != 0f32 {
+            wheel_x
+        } else {
+            wheel_y
+        }))
+    } else if wheel_y != 0f32 {
+        Some(Command::ScrollVertical(wheel_y))
     } else if is_mouse_button_pressed(MouseButton::Left) {
         Some(Command::MouseLeftClick)
+    } else if is_mouse_button_pressed(MouseButton::Middle) {
+        Some(Command::MouseMiddleClick)
     } else if input::is_mouse_button_down(MouseButton::Left) {
         Some(Command::MouseDown)
     } else if is_key_pressed(KeyCode::PageDown) {
@@ -144,14 +292,12 @@ pub fn get_command() -> Option<Command> {
         Some(Command::Delete)
     } else if is_key_pressed(KeyCode::Backspace) {
         Some(Command::Backspace)
-    } else if !is_key_down(KeyCode::LeftControl) && input::get_last_key_pressed().is_some() {
+    } else if !is_key_down(KeyCode::LeftControl)
+        && !is_key_down(KeyCode::LeftAlt)
+        && input::get_last_key_pressed().is_some()
+    {
         if let Some(c) = input::get_char_pressed() {
-            if !(c.is_alphanumeric()
-                || c == ' '
-                || c == '\t'
-                || c.is_ascii_punctuation()
-                || c.is_ascii_graphic())
-            {
+            if c.is_control() && c != '\t' {
                 return None;
             }
             if is_key_down(KeyCode::LeftShift) {
@@ -168,7 +314,192 @@ pub fn get_command() -> Option<Command> {
 }
 
 fn update_view_buffer(ctx: &mut Context) {
-    ctx.vert_cell_count.1 = screen_height() as usize / ctx.font_size as usize + 1;
+    if let Some((_, rect)) = pane_rects(ctx)
+        .into_iter()
+        .find(|(id, _)| *id == ctx.active_pane)
+    {
+        ctx.viewport = rect;
+    }
+    ctx.vert_cell_count.1 = ctx.viewport.h as usize / ctx.font_size as usize + 1;
+    // Anything moving the cursor lines the rows up again.
+    ctx.scroll_px = 0f32;
+    ctx.scroll_pending = 0f32;
+    scroll_to_cursor(ctx);
+    from_str_to_cells(ctx);
+}
+
+// Last line that is still fully visible below the first line of the view.
+fn last_visible_line(ctx: &Context) -> usize {
+    let max_row = ctx.vert_cell_count.1.saturating_sub(2);
+    let last_line = ctx.buffer.line_count() - 1;
+    if !ctx.is_soft_wrap {
+        return std::cmp::min(ctx.vert_cell_count.0 + max_row, last_line);
+    }
+    let mut line = ctx.vert_cell_count.0;
+    let mut used = line_wrap_starts(ctx, line).len();
+    while line < last_line {
+        let next = line_wrap_starts(ctx, line + 1).len();
+        if used + next > max_row + 1 {
+            break;
+        }
+        used += next;
+        line += 1;
+    }
+    line
+}
+
+// Moves the view by whole lines. The cursor keeps its line while that stays on
+// screen and is dragged along with the view otherwise.
+fn scroll_view(ctx: &mut Context, lines: isize) {
+    let cursor_line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    let last_line = ctx.buffer.line_count() - 1;
+    ctx.vert_cell_count.0 =
+        (ctx.vert_cell_count.0 as isize + lines).clamp(0, last_line as isize) as usize;
+    let line = cursor_line.clamp(ctx.vert_cell_count.0, last_visible_line(ctx));
+    if line != cursor_line {
+        ctx.curr_cursor_pos.0 = std::cmp::min(ctx.curr_cursor_pos.0, ctx.buffer.line_len(line));
+    }
+    ctx.curr_cursor_pos.1 = line - ctx.vert_cell_count.0;
+}
+
+// Smooth scrolling moves by pixels, the part of a line scrolled past is kept in
+// scroll_px and whole lines go to scroll_view.
+fn scroll_view_px(ctx: &mut Context, dy: f32) {
+    let line_h = ctx.font_size as f32;
+    let total = ctx.scroll_px + dy;
+    let first = ctx.vert_cell_count.0;
+    scroll_view(ctx, (total / line_h).floor() as isize);
+    let moved = ctx.vert_cell_count.0 as f32 - first as f32;
+    ctx.scroll_px = total - moved * line_h;
+    // Stopped by the top or the end of the buffer.
+    if ctx.scroll_px < 0f32
+        || ctx.scroll_px >= line_h
+        || ctx.vert_cell_count.0 + 1 >= ctx.buffer.line_count()
+    {
+        ctx.scroll_px = 0f32;
+        ctx.scroll_pending = 0f32;
+    }
+}
+
+// Wheel deltas add up in scroll_pending and are used up here every frame, a part
+// per frame when smooth scrolling is on.
+fn step_wheel_scroll(ctx: &mut Context) {
+    if ctx.scroll_pending == 0f32 {
+        return;
+    }
+    if ctx.is_smooth_scroll {
+        let step = if ctx.scroll_pending.abs() < 1f32 {
+            ctx.scroll_pending
+        } else {
+            ctx.scroll_pending * 0.3
+        };
+        ctx.scroll_pending -= step;
+        scroll_view_px(ctx, step);
+    } else {
+        // Trackpads send fractions of a line, they are kept until a line is full.
+        let line_h = ctx.font_size as f32;
+        let lines = (ctx.scroll_pending / line_h).trunc();
+        if lines == 0f32 {
+            return;
+        }
+        ctx.scroll_pending -= lines * line_h;
+        scroll_view(ctx, lines as isize);
+    }
+    from_str_to_cells(ctx);
+}
+
+// Follows the cursor after it moved. Wrapped rows are counted vertically, without
+// soft wrap the view scrolls sideways to keep horiz_margin columns around it.
+fn scroll_to_cursor(ctx: &mut Context) {
+    if ctx.is_soft_wrap {
+        ctx.horiz_offset = 0f32;
+        scroll_to_wrapped_cursor(ctx);
+        return;
+    }
+    let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    if line >= ctx.buffer.line_count() {
+        return;
+    }
+    let (x, w) = column_span(ctx, line, ctx.curr_cursor_pos.0);
+    let text_w = wrap_width(ctx, &ctx.buffer, ctx.viewport);
+    // Narrow panes cannot keep the whole margin on both sides.
+    let margin = (ctx.horiz_margin as f32 * column_width(ctx)).min(((text_w - w) / 2f32).max(0f32));
+    if x - margin < ctx.horiz_offset {
+        ctx.horiz_offset = (x - margin).max(0f32);
+    } else if x + w + margin > ctx.horiz_offset + text_w {
+        ctx.horiz_offset = x + w + margin - text_w;
+    }
+}
+
+// Scrolls sideways without moving the cursor, until the end of the widest visible
+// line reaches the right edge.
+fn scroll_horizontally(ctx: &mut Context, dx: f32) {
+    if ctx.is_soft_wrap {
+        return;
+    }
+    let text_x = ctx.viewport.x + gutter_width(ctx, &ctx.buffer);
+    let widest = ctx
+        .cells
+        .iter()
+        .map(|c| c.coord.0 + c.bound.0 + ctx.horiz_offset - text_x)
+        .fold(0f32, f32::max);
+    let max = (widest - wrap_width(ctx, &ctx.buffer, ctx.viewport)).max(0f32);
+    ctx.horiz_offset = (ctx.horiz_offset + dx).clamp(0f32, max);
+    from_str_to_cells(ctx);
+}
+
+// Index of the wrapped row of a line holding the column.
+fn wrap_segment(starts: &[usize], col: usize) -> usize {
+    starts.iter().rposition(|&s| s <= col).unwrap_or(0)
+}
+
+// Column offset chars into a wrapped row, clamped to the last column of that row.
+fn segment_col(starts: &[usize], seg: usize, offset: usize, line_len: usize) -> usize {
+    let end = starts.get(seg + 1).map_or(line_len, |&next| next - 1);
+    std::cmp::min(starts[seg] + offset, end)
+}
+
+// Lines above the cursor may take several rows each, scrolls down line by line
+// until the cursor row is fully visible again.
+fn scroll_to_wrapped_cursor(ctx: &mut Context) {
+    let cursor_line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    let max_row = ctx.vert_cell_count.1.saturating_sub(2);
+    let line_rows: Vec<usize> = (ctx.vert_cell_count.0..cursor_line)
+        .map(|l| line_wrap_starts(ctx, l).len())
+        .collect();
+    let starts = line_wrap_starts(ctx, cursor_line);
+    let mut cursor_row =
+        line_rows.iter().sum::<usize>() + wrap_segment(&starts, ctx.curr_cursor_pos.0);
+    for rows in line_rows {
+        if cursor_row <= max_row {
+            break;
+        }
+        cursor_row -= rows;
+        ctx.vert_cell_count.0 += 1;
+        ctx.curr_cursor_pos.1 -= 1;
+    }
+}
+
+// Scrolls so the cursor stays on a fully visible row and clamps it to the buffer,
+// needed whenever the pane shrinks or another pane edited the same buffer.
+fn keep_cursor_in_view(ctx: &mut Context) {
+    let last_line = ctx.buffer.line_count() - 1;
+    ctx.vert_cell_count.0 = std::cmp::min(ctx.vert_cell_count.0, last_line);
+    let max_row = ctx.vert_cell_count.1.saturating_sub(2);
+    if ctx.curr_cursor_pos.1 > max_row {
+        ctx.vert_cell_count.0 += ctx.curr_cursor_pos.1 - max_row;
+        ctx.curr_cursor_pos.1 = max_row;
+    }
+    if ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1 > last_line {
+        ctx.curr_cursor_pos.1 = last_line.saturating_sub(ctx.vert_cell_count.0);
+        ctx.vert_cell_count.0 = last_line - ctx.curr_cursor_pos.1;
+    }
+    ctx.curr_cursor_pos.0 = std::cmp::min(
+        ctx.curr_cursor_pos.0,
+        ctx.buffer
+            .line_len(ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1),
+    );
+    scroll_to_cursor(ctx);
     from_str_to_cells(ctx);
 }
 
@@ -200,84 +531,204 @@ pub async fn go_to_line(ctx: &mut Context) -> usize {
         .unwrap_or(ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1 + 1);
 }
 
-pub async fn find_in_buf(ctx: &mut Context, is_case_sensitive: bool) -> (usize, usize) {
+fn search_query(ctx: &Context, is_case_sensitive: bool) -> Query {
+    Query {
+        pattern: ctx.prompt_input.clone(),
+        is_case_sensitive,
+        is_regex: ctx.is_regex_search,
+        is_whole_word: ctx.is_whole_word_search,
+        scope: ctx.search_scope.filter(|_| ctx.is_search_in_selection),
+    }
+}
+
+// Whole text without the trailing '\n' the buffer always keeps, so a match can
+// never remove it.
+fn search_haystack(ctx: &Context) -> String {
+    ctx.buffer.slice(0..ctx.buffer.len_chars() - 1)
+}
+
+// Drops the results of the previous pattern and starts searching for the current
+// one, the view jumps to the first match after search_origin once it is found.
+fn restart_search(ctx: &mut Context, is_case_sensitive: bool) {
+    ctx.search_res.clear();
+    ctx.search_error = None;
+    ctx.search_regex = None;
+    ctx.last_searched_idx = 0;
+    if ctx.prompt_input.is_empty() {
+        ctx.search_job = None;
+        ctx.is_search_jump_pending = false;
+        restore_search_origin(ctx);
+        return;
+    }
+    ctx.search_job = Some(SearchJob::spawn(
+        ctx.buffer.snapshot(),
+        search_query(ctx, is_case_sensitive),
+    ));
+    ctx.is_search_jump_pending = true;
+}
+
+// Handles the Alt shortcuts shared by the find and replace prompts, returns
+// whether the key was one of them.
+fn toggle_search_option(ctx: &mut Context, key: KeyCode, is_case_sensitive: bool) -> bool {
+    match key {
+        KeyCode::R => ctx.is_regex_search = !ctx.is_regex_search,
+        KeyCode::W => ctx.is_whole_word_search = !ctx.is_whole_word_search,
+        KeyCode::L if ctx.search_scope.is_some() => {
+            ctx.is_search_in_selection = !ctx.is_search_in_selection
+        }
+        _ => return false,
+    }
+    restart_search(ctx, is_case_sensitive);
+    true
+}
+
+// The selection becomes the scope of the in-selection option and is hidden while
+// the prompt moves the view around.
+fn begin_search(ctx: &mut Context, is_case_sensitive: bool) {
     ctx.prompt_input = ctx.last_searched_term.clone();
+    ctx.search_origin = (ctx.curr_cursor_pos, ctx.vert_cell_count.0);
+    ctx.search_selection = ctx.selection_range.take();
+    ctx.search_scope = ctx
+        .search_selection
+        .map(|((a, _), (b, _))| (std::cmp::min(a, b), std::cmp::max(a, b) + 1));
+    ctx.is_search_in_selection = false;
+    restart_search(ctx, is_case_sensitive);
+}
+
+fn poll_search(ctx: &mut Context) {
+    let Some(job) = &ctx.search_job else {
+        return;
+    };
+    let mut is_done = false;
+    for msg in job.poll() {
+        match msg {
+            SearchMsg::Matches(batch) => {
+                for (start, end) in batch {
+                    ctx.search_res.push((ctx.search_res.len(), start, end));
+                }
+            }
+            SearchMsg::Error(e) => ctx.search_error = Some(e),
+            SearchMsg::Done => is_done = true,
+        }
+    }
+    if is_done {
+        ctx.search_job = None;
+    }
+    if !ctx.is_search_jump_pending {
+        return;
+    }
+    let (pos, first_line) = ctx.search_origin;
+    let origin = (first_line + pos.1, pos.0);
+    if let Some(idx) = ctx
+        .search_res
+        .iter()
+        .position(|&(_, start, _)| (start.1, start.0) >= origin)
+    {
+        ctx.last_searched_idx = idx;
+        ctx.is_search_jump_pending = false;
+        jump_to_match(ctx);
+    } else if is_done {
+        ctx.is_search_jump_pending = false;
+        if ctx.search_res.is_empty() {
+            restore_search_origin(ctx);
+        } else {
+            ctx.last_searched_idx = 0;
+            jump_to_match(ctx);
+        }
+    }
+}
+
+// Leaves the find or replace prompt the way it was entered, with the selection
+// back. Its end follows the text replaced inside it, like the search scope, and
+// a selection whose text was replaced by nothing is dropped.
+fn cancel_search(ctx: &mut Context) {
+    ctx.search_job = None;
+    restore_search_origin(ctx);
+    ctx.selection_range = match (ctx.search_selection.take(), ctx.search_scope) {
+        (Some(_), Some((start, end))) if end <= start => None,
+        (Some((mut a, mut b)), Some((_, end))) => {
+            if a.0 > b.0 {
+                a.0 = end - 1;
+            } else {
+                b.0 = end - 1;
+            }
+            Some((a, b))
+        }
+        (sel, _) => sel,
+    };
+    ctx.prompt_input.clear();
+}
+
+fn restore_search_origin(ctx: &mut Context) {
+    let (pos, first_line) = ctx.search_origin;
+    ctx.curr_cursor_pos = pos;
+    ctx.vert_cell_count.0 = first_line;
+    update_view_buffer(ctx);
+    keep_cursor_in_view(ctx);
+}
+
+fn jump_to_match(ctx: &mut Context) {
+    let pos = ctx
+        .search_res
+        .iter()
+        .find(|&&(idx, _, _)| idx == ctx.last_searched_idx)
+        .unwrap()
+        .1;
+    ctx.vert_cell_count.0 = pos.1;
+    ctx.curr_cursor_pos = (pos.0, 0);
+    update_view_buffer(ctx);
+}
+
+fn step_search_match(ctx: &mut Context, is_forward: bool) {
+    if ctx.search_res.is_empty() {
+        return;
+    }
+    let len = ctx.search_res.len();
+    ctx.last_searched_idx = if is_forward {
+        (ctx.last_searched_idx + 1) % len
+    } else {
+        (ctx.last_searched_idx + len - 1) % len
+    };
+    ctx.is_search_jump_pending = false;
+    jump_to_match(ctx);
+}
+
+pub async fn find_in_buf(ctx: &mut Context, is_case_sensitive: bool) {
+    begin_search(ctx, is_case_sensitive);
     let _ = input::get_char_pressed();
     loop {
+        poll_search(ctx);
         if let Some(key) = input::get_last_key_pressed() {
             if key == KeyCode::Escape {
-                ctx.is_search_changed = false;
-                ctx.prompt_input.clear();
-                return (
-                    ctx.curr_cursor_pos.0,
-                    ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1,
-                );
+                cancel_search(ctx);
+                return;
+            }
+            if is_key_down(KeyCode::LeftAlt) {
+                toggle_search_option(ctx, key, is_case_sensitive);
+                while input::get_char_pressed().is_some() {}
+                render(ctx).await;
+                continue;
             }
             if key == KeyCode::Backspace {
                 ctx.prompt_input.pop();
                 ctx.last_searched_term = ctx.prompt_input.clone();
+                restart_search(ctx, is_case_sensitive);
             }
             if key == KeyCode::Enter {
                 let _ = input::get_char_pressed();
-                if ctx.is_search_changed {
-                    let mut searchidx: usize = 0;
-                    let mut searchres: SearchResults = Default::default();
-                    for (i, l) in ctx.buffer.buf.lines().enumerate() {
-                        if is_case_sensitive == false {
-                            let s = l.to_ascii_lowercase();
-                            let p = ctx.prompt_input.to_ascii_lowercase();
-                            for (idx, _) in s.match_indices(&p) {
-                                searchres.push((searchidx, (idx, i)));
-                                searchidx = searchidx.saturating_add(1);
-                            }
-                        } else {
-                            for (idx, _) in l.match_indices(&ctx.prompt_input) {
-                                searchres.push((searchidx, (idx, i)));
-                                searchidx = searchidx.saturating_add(1);
-                            }
-                        }
-                    }
-                    ctx.search_res = searchres;
-                    if ctx.last_searched_idx >= ctx.search_res.len() {
-                        ctx.last_searched_idx = 0;
-                    }
-                } else {
-                    if ctx.search_res.is_empty() {
-                        return (
-                            ctx.curr_cursor_pos.0,
-                            ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1,
-                        );
-                    }
-                    if is_key_down(KeyCode::LeftShift) {
-                        if (ctx.last_searched_idx as isize - 1) >= 0 {
-                            ctx.last_searched_idx -= 1;
-                        } else {
-                            ctx.last_searched_idx = ctx.search_res.len() - 1;
-                        }
-                    } else {
-                        if (ctx.last_searched_idx + 1) < ctx.search_res.len() {
-                            ctx.last_searched_idx += 1;
-                        } else {
-                            ctx.last_searched_idx = 0;
-                        }
-                    }
-                    let pos = ctx
-                        .search_res
-                        .iter()
-                        .find(|&&(idx, _)| idx == ctx.last_searched_idx)
-                        .unwrap()
-                        .1;
-                    ctx.vert_cell_count.0 = pos.1;
-                    ctx.curr_cursor_pos = (pos.0, 0);
+                // Ctrl-Enter keeps the cursor on the current match and leaves the prompt.
+                if ctx.search_res.is_empty() || is_key_down(KeyCode::LeftControl) {
+                    ctx.search_job = None;
+                    ctx.prompt_input.clear();
+                    return;
                 }
-                ctx.is_search_changed = false;
-                update_view_buffer(ctx);
+                step_search_match(ctx, !is_key_down(KeyCode::LeftShift));
             }
             if let Some(c) = input::get_char_pressed() {
-                if c.is_ascii_graphic() || c.is_ascii_whitespace() {
-                    ctx.is_search_changed = true;
+                if !c.is_control() || c == '\t' {
                     ctx.prompt_input.push(c);
                     ctx.last_searched_term = ctx.prompt_input.clone();
+                    restart_search(ctx, is_case_sensitive);
                 }
             }
         }
@@ -285,280 +736,1121 @@ pub async fn find_in_buf(ctx: &mut Context, is_case_sensitive: bool) -> (usize,
     }
 }
 
-pub async fn show_help_page(ctx: &mut Context) {
-    loop {
-        if let Some(k) = input::get_last_key_pressed() {
-            if k == KeyCode::Escape {
-                return;
-            }
-        }
-        render(ctx).await;
+// Replacement for the match starting at the given byte, capture groups like $1
+// or ${name} are expanded in regex mode.
+fn expand_replacement(template: &str, re: Option<&Regex>, text: &str, byte: usize) -> String {
+    let mut new = String::new();
+    match re.and_then(|re| re.captures_at(text, byte)) {
+        Some(caps) => caps.expand(template, &mut new),
+        None => new.push_str(template),
     }
+    new
 }
 
-pub async fn prompt_unsaved_changes(ctx: &mut Context) {
-    loop {
-        if let Some(c) = input::get_char_pressed() {
-            if c == 'y' {
-                ctx.is_exit = true;
-                return;
-            } else if c == 'n' {
-                return;
-            }
-        }
-        render(ctx).await;
+// (char offset, matched text, replacement) of a single match. Patterns run in
+// multi-line mode, so the lines the match spans are enough to expand it again.
+fn replacement_for(ctx: &Context, query: &Query, m: Match) -> (usize, String, String) {
+    let ((col, line), (end_col, end_line)) = m;
+    let start = ctx.buffer.line_to_char(line) + col;
+    let end = ctx.buffer.line_to_char(end_line) + end_col;
+    let old = ctx.buffer.slice(start..end);
+    if !query.is_regex {
+        return (start, old, ctx.replace_input.clone());
     }
+    let lines_end = std::cmp::min(
+        ctx.buffer.line_to_char(end_line + 1),
+        ctx.buffer.len_chars() - 1,
+    );
+    let text = ctx.buffer.slice(ctx.buffer.line_to_char(line)..lines_end);
+    let byte = text.char_indices().nth(col).map_or(text.len(), |(b, _)| b);
+    (
+        start,
+        old,
+        expand_replacement(&ctx.replace_input, ctx.search_regex.as_ref(), &text, byte),
+    )
 }
 
-fn move_cursor_up(ctx: &mut Context) {
-    if ctx.vert_cell_count.0 == 0 && ctx.curr_cursor_pos.1 == 0 {
-        return ();
-    }
-    let inter_buf_off = get_cursor_pos_to_buf_offset(ctx).unwrap();
-    if ctx.curr_cursor_pos.1 == 0 && inter_buf_off.0.is_some() {
-        ctx.vert_cell_count.0 -= 1;
-        ctx.curr_cursor_pos = (0, 0);
-        update_view_buffer(ctx);
-        return ();
-    }
-    let view_buffer = from_cells_to_string(&ctx.cells);
-    if ctx
-        .cells
-        .iter()
-        .find(|c| (c.pos.1 == ctx.curr_cursor_pos.1 - 1) && (c.pos.0 == ctx.curr_cursor_pos.0))
-        .is_some()
-    {
-        ctx.curr_cursor_pos.1 -= 1;
-    } else {
-        ctx.curr_cursor_pos.0 = view_buffer[ctx.curr_cursor_pos.1 - 1]
-            .char_indices()
-            .last()
-            .unwrap()
-            .0;
-        ctx.curr_cursor_pos.1 -= 1;
-    }
-    update_view_buffer(ctx);
+// Replacements for every match of the current pattern, found synchronously so
+// replace-all never depends on how far the background search got.
+fn all_replacements(ctx: &Context, query: &Query) -> Vec<(usize, String, String)> {
+    let text = search_haystack(ctx);
+    let re = ctx.search_regex.as_ref().filter(|_| query.is_regex);
+    let mut res = Vec::new();
+    let (mut last_byte, mut last_char) = (0usize, 0usize);
+    let _ = for_each_match(&text, query, &mut |range| {
+        let start = last_char + text[last_byte..range.start].chars().count();
+        (last_byte, last_char) = (range.start, start);
+        let new = expand_replacement(&ctx.replace_input, re, &text, range.start);
+        res.push((start, text[range].to_owned(), new));
+        true
+    });
+    res
 }
 
-fn move_cursor_down(ctx: &mut Context) {
-    if ctx.vert_cell_count.0 == ctx.buffer.buf.lines().count() - 1
-        || (ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1) == (ctx.buffer.buf.lines().count() - 1)
-    {
-        return ();
-    }
-    let inter_buf_off = get_cursor_pos_to_buf_offset(ctx).unwrap();
-    if ctx.curr_cursor_pos.1 == (ctx.vert_cell_count.1 - 1)
-        && ctx.buffer.buf[inter_buf_off.1..]
-            .chars()
-            .find(|&c| c == '\n')
-            .is_some()
-    {
-        ctx.vert_cell_count.0 += 1;
-        ctx.curr_cursor_pos = (0, ctx.vert_cell_count.1 - 2);
-        update_view_buffer(ctx);
-        return ();
+fn replace_matches(
+    ctx: &mut Context,
+    record: &mut Record<Change>,
+    is_case_sensitive: bool,
+    only: Option<usize>,
+) {
+    let query = search_query(ctx, is_case_sensitive);
+    if query.is_regex && ctx.search_regex.is_none() {
+        ctx.search_regex = build_regex(&query).ok();
     }
-    let view_buffer = from_cells_to_string(&ctx.cells);
-    if ctx
-        .cells
-        .iter()
-        .find(|c| (c.pos.1 == ctx.curr_cursor_pos.1 + 1) && (c.pos.0 == ctx.curr_cursor_pos.0))
-        .is_some()
-    {
-        ctx.curr_cursor_pos.1 += 1
+    let replacements = match only {
+        Some(idx) => {
+            let (_, start, end) = ctx.search_res[idx];
+            vec![replacement_for(ctx, &query, (start, end))]
+        }
+        None => all_replacements(ctx, &query),
+    };
+    let Some(&(first, _, ref new)) = replacements.first() else {
+        return;
+    };
+    // Stepping continues with the first match after the replaced text.
+    let resume_at = if only.is_some() {
+        first + new.chars().count()
     } else {
-        if view_buffer.get(ctx.curr_cursor_pos.1 + 1).is_none() {
-            return;
+        first
+    };
+    // The scope has to grow or shrink with the text replaced inside it.
+    if let Some((_, end)) = ctx.search_scope.as_mut() {
+        for (_, old, new) in replacements.iter() {
+            *end = (*end + new.chars().count()).saturating_sub(old.chars().count());
         }
-        ctx.curr_cursor_pos.0 = view_buffer[ctx.curr_cursor_pos.1 + 1]
-            .char_indices()
-            .last()
-            .unwrap()
-            .0;
-        ctx.curr_cursor_pos.1 += 1
     }
+    ctx.is_file_changed = true;
+    record.apply(ctx, Change::Replace(replacements));
+    ctx.curr_cursor_pos = get_view_pos_from_internal_off(ctx, resume_at);
+    ctx.search_origin = (ctx.curr_cursor_pos, ctx.vert_cell_count.0);
     update_view_buffer(ctx);
+    restart_search(ctx, is_case_sensitive);
 }
 
-fn move_cursor_left(ctx: &mut Context) {
-    if ctx.curr_cursor_pos.0 == 0 {
-        ()
-    } else {
-        ctx.curr_cursor_pos.0 -= 1
+pub async fn replace_in_buf(
+    ctx: &mut Context,
+    record: &mut Record<Change>,
+    is_case_sensitive: bool,
+) {
+    ctx.is_replace_input_active = false;
+    begin_search(ctx, is_case_sensitive);
+    let _ = input::get_char_pressed();
+    loop {
+        poll_search(ctx);
+        if let Some(key) = input::get_last_key_pressed() {
+            if key == KeyCode::Escape {
+                cancel_search(ctx);
+                return;
+            }
+            if is_key_down(KeyCode::LeftAlt) {
+                if !toggle_search_option(ctx, key, is_case_sensitive)
+                    && key == KeyCode::A
+                    && ctx.search_error.is_none()
+                {
+                    replace_matches(ctx, record, is_case_sensitive, None);
+                }
+                while input::get_char_pressed().is_some() {}
+                render(ctx).await;
+                continue;
+            }
+            match key {
+                KeyCode::Tab => {
+                    ctx.is_replace_input_active = !ctx.is_replace_input_active;
+                    let _ = input::get_char_pressed();
+                }
+                KeyCode::Backspace => {
+                    if ctx.is_replace_input_active {
+                        ctx.replace_input.pop();
+                    } else {
+                        ctx.prompt_input.pop();
+                        ctx.last_searched_term = ctx.prompt_input.clone();
+                        restart_search(ctx, is_case_sensitive);
+                    }
+                }
+                KeyCode::Enter => {
+                    let _ = input::get_char_pressed();
+                    if !ctx.search_res.is_empty() && !ctx.is_search_jump_pending {
+                        replace_matches(
+                            ctx,
+                            record,
+                            is_case_sensitive,
+                            Some(ctx.last_searched_idx),
+                        );
+                    }
+                }
+                KeyCode::Down => step_search_match(ctx, true),
+                KeyCode::Up => step_search_match(ctx, false),
+                _ => (),
+            }
+            if let Some(c) = input::get_char_pressed() {
+                if !c.is_control() || c == '\t' {
+                    if ctx.is_replace_input_active {
+                        ctx.replace_input.push(c);
+                    } else {
+                        ctx.prompt_input.push(c);
+                        ctx.last_searched_term = ctx.prompt_input.clone();
+                        restart_search(ctx, is_case_sensitive);
+                    }
+                }
+            }
+        }
+        render(ctx).await;
     }
 }
 
-fn move_cursor_right(ctx: &mut Context) {
-    let view_buffer = from_cells_to_string(&ctx.cells);
-    if view_buffer[ctx.curr_cursor_pos.1]
-        .chars()
-        .nth(ctx.curr_cursor_pos.0)
-        .unwrap()
-        == '\n'
-    {
-        ()
-    } else {
-        ctx.curr_cursor_pos.0 += 1
+// Up/Down/PageUp/PageDown move the selection of a pickable overlay list.
+fn move_overlay_selection(ctx: &mut Context, key: KeyCode, rows: usize) {
+    let last = ctx.overlay_lines.len().saturating_sub(1);
+    match key {
+        KeyCode::Up => ctx.overlay_selected = ctx.overlay_selected.saturating_sub(1),
+        KeyCode::Down => ctx.overlay_selected = std::cmp::min(ctx.overlay_selected + 1, last),
+        KeyCode::PageUp => ctx.overlay_selected = ctx.overlay_selected.saturating_sub(rows),
+        KeyCode::PageDown => {
+            ctx.overlay_selected = std::cmp::min(ctx.overlay_selected + rows, last)
+        }
+        _ => (),
+    }
+    if ctx.overlay_selected < ctx.overlay_scroll {
+        ctx.overlay_scroll = ctx.overlay_selected;
+    } else if ctx.overlay_selected >= ctx.overlay_scroll + rows {
+        ctx.overlay_scroll = ctx.overlay_selected + 1 - rows;
     }
 }
 
-fn move_cursor_left_word(ctx: &mut Context) {
-    let view_buffer = from_cells_to_string(&ctx.cells);
-    let row = &view_buffer[ctx.curr_cursor_pos.1];
-    let sub = &row[..ctx.curr_cursor_pos.0];
-    if let Some(space_indx) = sub.rfind(' ') {
-        ctx.curr_cursor_pos.0 -= ctx.curr_cursor_pos.0 - space_indx;
-    }
+// Rows of an overlay list shown above a prompt on the last row.
+fn overlay_rows(ctx: &Context) -> usize {
+    std::cmp::max(screen_height() as usize / ctx.font_size as usize, 2) - 1
 }
 
-fn move_cursor_right_word(ctx: &mut Context) {
-    let view_buffer = from_cells_to_string(&ctx.cells);
-    let row = &view_buffer[ctx.curr_cursor_pos.1];
-    let sub = &row[ctx.curr_cursor_pos.0..];
-    if let Some(space_indx) = sub.find(' ') {
-        ctx.curr_cursor_pos.0 += space_indx + 1;
-    }
+// Find in files starts from the directory of the active document.
+fn file_search_root(ctx: &Context) -> PathBuf {
+    let dir = match ctx.active_buf.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf())
 }
 
-#[derive(Debug)]
-pub struct InternalBufOffset(Option<usize>, usize);
-impl InternalBufOffset {
-    pub fn get_inline_offset(&self) -> usize {
-        if self.0.is_some() {
-            self.1 - self.0.unwrap() - 1
-        } else {
-            self.1
-        }
-    }
+fn file_match_line(m: &FileMatch) -> String {
+    format!(
+        "{}:{}:{}: {}",
+        m.path.display(),
+        m.line + 1,
+        m.col + 1,
+        m.snippet
+    )
 }
 
-fn get_cursor_pos_to_buf_offset(ctx: &Context) -> Option<InternalBufOffset> {
-    if ctx.vert_cell_count.0 == 0 && ctx.curr_cursor_pos.1 == 0 {
-        return Some(InternalBufOffset(None, ctx.curr_cursor_pos.0));
-    }
-    ctx.buffer
-        .buf
-        .match_indices('\n')
-        .nth((ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1).saturating_sub(1))
-        .map(|(index, _)| InternalBufOffset(Some(index), index + ctx.curr_cursor_pos.0 + 1))
+fn restart_file_search(ctx: &mut Context) {
+    ctx.file_search_res.clear();
+    ctx.file_search_error = None;
+    ctx.overlay_lines.clear();
+    ctx.overlay_selected = 0;
+    ctx.overlay_scroll = 0;
+    ctx.file_search_term = ctx.prompt_input.clone();
+    ctx.file_search_job = if ctx.prompt_input.is_empty() {
+        None
+    } else {
+        let query = Query {
+            pattern: ctx.prompt_input.clone(),
+            is_case_sensitive: ctx.is_file_search_case_sensitive,
+            is_regex: ctx.is_regex_search,
+            is_whole_word: ctx.is_whole_word_search,
+            scope: None,
+        };
+        Some(FileSearchJob::spawn(ctx.file_search_root.clone(), query))
+    };
 }
 
-pub fn get_ch_off_to_inline_off(ctx: &Context, off: usize) -> usize {
-    let mut offset = 0usize;
-    for i in (0..off).rev() {
-        if ctx.buffer.buf.chars().nth(i).unwrap() != '\n' {
-            offset += 1;
-        } else {
-            return offset;
+fn poll_file_search(ctx: &mut Context) {
+    let Some(job) = &ctx.file_search_job else {
+        return;
+    };
+    let mut is_done = false;
+    for msg in job.poll() {
+        match msg {
+            FileSearchMsg::Matches(batch) => {
+                for m in batch {
+                    ctx.overlay_lines.push(file_match_line(&m));
+                    ctx.file_search_res.push(m);
+                }
+            }
+            FileSearchMsg::Error(e) => ctx.file_search_error = Some(e),
+            FileSearchMsg::Done => is_done = true,
         }
     }
-    offset
+    if is_done {
+        ctx.file_search_job = None;
+    }
 }
 
-fn delete_selection(ctx: &mut Context, record: &mut Record<Change>) -> String {
-    let deleted_str;
-    if ctx.selection_range.unwrap().0 .0 == ctx.selection_range.unwrap().1 .0
-        && ctx.selection_range.unwrap().0 .0 == ctx.buffer.buf.len() - 1
-    {
-        return String::from("");
+// Shows the results of the last search again as long as the root did not change.
+// Enter runs the typed pattern, or opens the selected match once its results are
+// listed.
+async fn find_in_files(ctx: &mut Context) -> Option<usize> {
+    let root = file_search_root(ctx);
+    if root != ctx.file_search_root {
+        ctx.file_search_root = root;
+        ctx.file_search_res.clear();
+        ctx.file_search_term.clear();
     }
+    ctx.prompt_input = ctx.file_search_term.clone();
+    ctx.is_search_in_selection = false;
+    ctx.overlay_lines = ctx.file_search_res.iter().map(file_match_line).collect();
+    ctx.overlay_selected = std::cmp::min(
+        ctx.overlay_selected,
+        ctx.overlay_lines.len().saturating_sub(1),
+    );
+    let _ = input::get_char_pressed();
+    let res = loop {
+        poll_file_search(ctx);
+        if let Some(key) = input::get_last_key_pressed() {
+            if key == KeyCode::Escape {
+                break None;
+            }
+            if is_key_down(KeyCode::LeftAlt) {
+                match key {
+                    KeyCode::C => {
+                        ctx.is_file_search_case_sensitive = !ctx.is_file_search_case_sensitive
+                    }
+                    KeyCode::R => ctx.is_regex_search = !ctx.is_regex_search,
+                    KeyCode::W => ctx.is_whole_word_search = !ctx.is_whole_word_search,
+                    _ => (),
+                }
+                while input::get_char_pressed().is_some() {}
+                render(ctx).await;
+                continue;
+            }
+            match key {
+                KeyCode::Enter => {
+                    let _ = input::get_char_pressed();
+                    if ctx.prompt_input != ctx.file_search_term
+                        || (ctx.file_search_res.is_empty() && ctx.file_search_job.is_none())
+                    {
+                        restart_file_search(ctx);
+                    } else if !ctx.file_search_res.is_empty() {
+                        break Some(ctx.overlay_selected);
+                    }
+                }
+                KeyCode::Backspace => {
+                    ctx.prompt_input.pop();
+                }
+                _ => move_overlay_selection(ctx, key, overlay_rows(ctx)),
+            }
+            if let Some(c) = input::get_char_pressed() {
+                if !c.is_control() || c == '\t' {
+                    ctx.prompt_input.push(c);
+                }
+            }
+        }
+        render(ctx).await;
+    };
+    ctx.file_search_job = None;
+    ctx.overlay_lines.clear();
+    res
+}
 
-    if ctx.selection_range.unwrap().1 .0 == ctx.buffer.buf.len() - 1 {
-        ctx.buffer.buf.push('\n');
-    } else if ctx.selection_range.unwrap().0 .0 == ctx.buffer.buf.len() - 1 {
-        ctx.buffer.buf.push('\n');
-    }
+pub fn remember_recent_file(ctx: &mut Context, file: &Path) {
+    let file = std::fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    ctx.recent_files.retain(|f| *f != file);
+    ctx.recent_files.insert(0, file);
+    ctx.recent_files.truncate(MAX_RECENT_FILES);
+}
 
-    if ctx.selection_range.unwrap().0 .0 < ctx.selection_range.unwrap().1 .0 {
-        ctx.curr_cursor_pos = ctx.selection_range.unwrap().0 .1;
-        deleted_str = String::from(
-            &ctx.buffer.buf[ctx.selection_range.unwrap().0 .0..=ctx.selection_range.unwrap().1 .0],
-        );
-        record.apply(
-            ctx,
-            Change::DeleteSelection(
-                ctx.selection_range.unwrap().0 .0,
-                String::from(
-                    &ctx.buffer.buf
-                        [ctx.selection_range.unwrap().0 .0..=ctx.selection_range.unwrap().1 .0],
-                ),
-            ),
-        );
+// Ranks the files from first on, a new pattern ranks them all again while files
+// streaming in from the index are merged into the ranking so far.
+fn rank_quick_open(ctx: &mut Context, first: usize) {
+    let recent: Vec<PathBuf> = ctx
+        .recent_files
+        .iter()
+        .filter_map(|f| f.strip_prefix(&ctx.quick_open_root).ok())
+        .map(Path::to_path_buf)
+        .collect();
+    let ranked = rank_files(
+        &ctx.quick_open_files[first..],
+        first,
+        &ctx.prompt_input,
+        &recent,
+    );
+    let is_pattern_changed = first == 0;
+    if is_pattern_changed {
+        ctx.quick_open_res = ranked;
     } else {
-        ctx.curr_cursor_pos = ctx.selection_range.unwrap().1 .1;
-        deleted_str = String::from(
-            &ctx.buffer.buf[ctx.selection_range.unwrap().1 .0..=ctx.selection_range.unwrap().0 .0],
-        );
-        record.apply(
-            ctx,
-            Change::DeleteSelection(
-                ctx.selection_range.unwrap().1 .0,
-                String::from(
-                    &ctx.buffer.buf
-                        [ctx.selection_range.unwrap().1 .0..=ctx.selection_range.unwrap().0 .0],
-                ),
-            ),
+        ctx.quick_open_res = merge_ranked(std::mem::take(&mut ctx.quick_open_res), ranked);
+    }
+    // Lines of files ranked before keep their relative order, so they are reused.
+    let mut old_lines = std::mem::take(&mut ctx.overlay_lines).into_iter();
+    ctx.overlay_lines = ctx
+        .quick_open_res
+        .iter()
+        .map(|&(i, _)| {
+            if i < first {
+                old_lines.next().unwrap_or_default()
+            } else {
+                ctx.quick_open_files[i].display().to_string()
+            }
+        })
+        .collect();
+    // Files streaming in while the index is built keep the selection where it is.
+    if is_pattern_changed {
+        ctx.overlay_selected = 0;
+        ctx.overlay_scroll = 0;
+    } else {
+        ctx.overlay_selected = std::cmp::min(
+            ctx.overlay_selected,
+            ctx.overlay_lines.len().saturating_sub(1),
         );
     }
+}
+
+fn poll_quick_open(ctx: &mut Context) {
+    let Some(job) = &ctx.quick_open_job else {
+        return;
+    };
+    let first = ctx.quick_open_files.len();
+    let mut is_done = false;
+    for msg in job.poll() {
+        match msg {
+            IndexMsg::Files(files) => ctx.quick_open_files.extend(files),
+            IndexMsg::Done => is_done = true,
+        }
+    }
+    if is_done {
+        ctx.quick_open_job = None;
+    }
+    if ctx.quick_open_files.len() > first {
+        rank_quick_open(ctx, first);
+    }
+}
+
+// Indexes the project of the active document and lets the user pick a file from
+// it by typing parts of its path.
+async fn quick_open(ctx: &mut Context) -> Option<PathBuf> {
+    ctx.quick_open_root = project_root(&ctx.active_buf);
+    ctx.quick_open_files.clear();
+    ctx.quick_open_job = Some(FileIndexJob::spawn(ctx.quick_open_root.clone()));
+    ctx.prompt_input.clear();
+    rank_quick_open(ctx, 0);
+    let _ = input::get_char_pressed();
+    let res = loop {
+        poll_quick_open(ctx);
+        if let Some(key) = input::get_last_key_pressed() {
+            match key {
+                KeyCode::Escape => break None,
+                KeyCode::Enter => {
+                    if let Some(&(idx, _)) = ctx.quick_open_res.get(ctx.overlay_selected) {
+                        break Some(ctx.quick_open_root.join(&ctx.quick_open_files[idx]));
+                    }
+                }
+                KeyCode::Backspace => {
+                    ctx.prompt_input.pop();
+                    rank_quick_open(ctx, 0);
+                }
+                _ => move_overlay_selection(ctx, key, overlay_rows(ctx)),
+            }
+            if let Some(c) = input::get_char_pressed() {
+                if !c.is_control() {
+                    ctx.prompt_input.push(c);
+                    rank_quick_open(ctx, 0);
+                }
+            }
+        }
+        render(ctx).await;
+    };
+    ctx.quick_open_job = None;
+    ctx.quick_open_files.clear();
+    ctx.quick_open_res.clear();
+    ctx.overlay_lines.clear();
+    res
+}
+
+async fn open_file_match(ctx: &mut Context, record: &mut Record<Change>, idx: usize) {
+    let m = &ctx.file_search_res[idx];
+    let (path, line, col) = (ctx.file_search_root.join(&m.path), m.line, m.col);
+    open_buffer(ctx, record, &path).await;
+    // The file may have changed on disk since it was searched.
+    let line = std::cmp::min(line, ctx.buffer.line_count() - 1);
     ctx.selection_range = None;
+    ctx.vert_cell_count.0 = line;
+    ctx.curr_cursor_pos = (std::cmp::min(col, ctx.buffer.line_len(line)), 0);
+    update_view_buffer(ctx);
+}
+
+pub async fn show_help_page(ctx: &mut Context) {
+    loop {
+        if let Some(k) = input::get_last_key_pressed() {
+            if k == KeyCode::Escape {
+                return;
+            }
+        }
+        render(ctx).await;
+    }
+}
+
+pub async fn prompt_unsaved_changes(ctx: &mut Context) -> bool {
+    loop {
+        if let Some(c) = input::get_char_pressed() {
+            if c == 'y' {
+                return true;
+            } else if c == 'n' {
+                return false;
+            }
+        }
+        render(ctx).await;
+    }
+}
+
+// Paths given on the command line, picked in a dialog or found by a search may
+// spell the same file differently.
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    a == b
+        || matches!(
+            (std::fs::canonicalize(a), std::fs::canonicalize(b)),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
+
+fn find_open_buffer(ctx: &Context, path: &Path) -> Option<usize> {
+    ctx.buffers.iter().enumerate().position(|(i, b)| {
+        if i == ctx.active_idx {
+            is_same_file(&ctx.active_buf, path)
+        } else {
+            is_same_file(&b.active_buf, path)
+        }
+    })
+}
+
+fn park_active_buffer(ctx: &mut Context, record: &mut Record<Change>) {
+    if ctx.is_swap_stale && ctx.swap_interval != 0 {
+        if let Err(e) = ctx.buffer.write_swap() {
+            eprintln!("Error occured while writing swap file: {e}");
+        }
+        ctx.is_swap_stale = false;
+    }
+    let slot = &mut ctx.buffers[ctx.active_idx];
+    slot.buffer = std::mem::take(&mut ctx.buffer);
+    slot.active_buf = std::mem::take(&mut ctx.active_buf);
+    slot.curr_cursor_pos = ctx.curr_cursor_pos;
+    slot.vert_first_line = ctx.vert_cell_count.0;
+    slot.horiz_offset = ctx.horiz_offset;
+    ctx.scroll_px = 0f32;
+    ctx.scroll_pending = 0f32;
+    slot.selection_range = ctx.selection_range.take();
+    ctx.extra_cursors.clear();
+    ctx.block_selection = None;
+    slot.is_file_changed = ctx.is_file_changed;
+    slot.is_swap_stale = ctx.is_swap_stale;
+    std::mem::swap(record, &mut slot.record);
+}
+
+fn load_buffer(ctx: &mut Context, record: &mut Record<Change>, idx: usize) {
+    ctx.active_idx = idx;
+    let slot = &mut ctx.buffers[idx];
+    ctx.buffer = std::mem::take(&mut slot.buffer);
+    ctx.active_buf = std::mem::take(&mut slot.active_buf);
+    ctx.curr_cursor_pos = slot.curr_cursor_pos;
+    ctx.vert_cell_count.0 = slot.vert_first_line;
+    ctx.horiz_offset = slot.horiz_offset;
+    ctx.selection_range = slot.selection_range.take();
+    ctx.is_file_changed = slot.is_file_changed;
+    ctx.is_swap_stale = slot.is_swap_stale;
+    std::mem::swap(record, &mut slot.record);
+    update_view_buffer(ctx);
+}
+
+fn switch_buffer(ctx: &mut Context, record: &mut Record<Change>, idx: usize) {
+    if idx == ctx.active_idx {
+        return;
+    }
+    park_active_buffer(ctx, record);
+    load_buffer(ctx, record, idx);
+}
+
+pub async fn open_buffer(ctx: &mut Context, record: &mut Record<Change>, file: &PathBuf) {
+    remember_recent_file(ctx, file);
+    if let Some(idx) = find_open_buffer(ctx, file) {
+        switch_buffer(ctx, record, idx);
+        return;
+    }
+    let mut buffer = Buffer::new(file);
+    if let Err(e) = buffer.read_to_buffer(file) {
+        ctx.status_msg = Some(format!("Error occured while opening file: {e}"));
+        return;
+    }
+    park_active_buffer(ctx, record);
+    ctx.buffers.push(BufferState {
+        buffer,
+        active_buf: file.to_owned(),
+        ..Default::default()
+    });
+    load_buffer(ctx, record, ctx.buffers.len() - 1);
+    if let Some(swap) = ctx.buffer.find_swap() {
+        ctx.mode = Modes::RecoverPrompt;
+        prompt_recover_swap(ctx, &swap).await;
+        ctx.mode = Modes::Edit;
+        update_view_buffer(ctx);
+    }
+}
+
+async fn close_active_buffer(
+    ctx: &mut Context,
+    record: &mut Record<Change>,
+    bell: &macroquad::audio::Sound,
+) {
+    if ctx.buffers.len() == 1 {
+        macroquad::audio::play_sound_once(*bell);
+        return;
+    }
+    if ctx.is_file_changed {
+        macroquad::audio::play_sound_once(*bell);
+        ctx.mode = Modes::ModifiedPrompt;
+        let discard = prompt_unsaved_changes(ctx).await;
+        ctx.mode = Modes::Edit;
+        if !discard {
+            return;
+        }
+    }
+    ctx.buffer.remove_swap();
+    let removed = ctx.active_idx;
+    ctx.buffers.remove(removed);
+    *record = Record::new();
+    let next = std::cmp::min(removed, ctx.buffers.len() - 1);
+    for pane in ctx.panes.iter_mut() {
+        if pane.buf_idx == removed {
+            *pane = Pane {
+                buf_idx: next,
+                ..Default::default()
+            };
+        } else if pane.buf_idx > removed {
+            pane.buf_idx -= 1;
+        }
+    }
+    load_buffer(ctx, record, next);
+}
+
+// Keeps the buffer index of every pane valid after two buffer slots traded places.
+fn swap_buffer_slots(ctx: &mut Context, a: usize, b: usize) {
+    ctx.buffers.swap(a, b);
+    for pane in ctx.panes.iter_mut() {
+        if pane.buf_idx == a {
+            pane.buf_idx = b;
+        } else if pane.buf_idx == b {
+            pane.buf_idx = a;
+        }
+    }
+}
+
+fn park_active_pane(ctx: &mut Context) {
+    ctx.panes[ctx.active_pane] = Pane {
+        buf_idx: ctx.active_idx,
+        curr_cursor_pos: ctx.curr_cursor_pos,
+        vert_first_line: ctx.vert_cell_count.0,
+        horiz_offset: ctx.horiz_offset,
+    };
+    ctx.scroll_px = 0f32;
+    ctx.scroll_pending = 0f32;
+    ctx.selection_range = None;
+    ctx.extra_cursors.clear();
+    ctx.block_selection = None;
+}
+
+fn enter_active_pane(ctx: &mut Context, record: &mut Record<Change>) {
+    let pane = ctx.panes[ctx.active_pane].clone();
+    switch_buffer(ctx, record, pane.buf_idx);
+    ctx.curr_cursor_pos = pane.curr_cursor_pos;
+    ctx.vert_cell_count.0 = pane.vert_first_line;
+    ctx.horiz_offset = pane.horiz_offset;
+    update_view_buffer(ctx);
+    keep_cursor_in_view(ctx);
+}
+
+fn focus_pane(ctx: &mut Context, record: &mut Record<Change>, id: usize) {
+    if id == ctx.active_pane {
+        return;
+    }
+    park_active_pane(ctx);
+    ctx.active_pane = id;
+    enter_active_pane(ctx, record);
+}
+
+fn split_active_pane(ctx: &mut Context, dir: SplitDir) {
+    park_active_pane(ctx);
+    let new_id = ctx.panes.len();
+    ctx.panes.push(ctx.panes[ctx.active_pane].clone());
+    ctx.layout.split_pane(ctx.active_pane, new_id, dir);
+    ctx.active_pane = new_id;
+    update_view_buffer(ctx);
+    keep_cursor_in_view(ctx);
+}
+
+fn close_active_pane(
+    ctx: &mut Context,
+    record: &mut Record<Change>,
+    bell: &macroquad::audio::Sound,
+) {
+    if ctx.panes.len() == 1 {
+        macroquad::audio::play_sound_once(*bell);
+        return;
+    }
+    ctx.panes.remove(ctx.active_pane);
+    ctx.layout.remove_pane(ctx.active_pane);
+    ctx.active_pane = std::cmp::min(ctx.active_pane, ctx.panes.len() - 1);
+    ctx.selection_range = None;
+    enter_active_pane(ctx, record);
+}
+
+// Nearest pane whose center lies in the (dx, dy) direction from the focused one.
+fn neighbour_pane(ctx: &Context, dx: f32, dy: f32) -> Option<usize> {
+    let rects = pane_rects(ctx);
+    let from = rects
+        .iter()
+        .find(|(id, _)| *id == ctx.active_pane)?
+        .1
+        .center();
+    rects
+        .iter()
+        .filter(|(id, _)| *id != ctx.active_pane)
+        .filter_map(|(id, rect)| {
+            let d = rect.center() - from;
+            let along = d.x * dx + d.y * dy;
+            let across = (d.x * dy).abs() + (d.y * dx).abs();
+            (along > 0f32).then_some((*id, along + across))
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(id, _)| id)
+}
+
+fn pane_under_cursor(ctx: &Context) -> Option<usize> {
+    let (x, y) = input::mouse_position();
+    pane_rects(ctx)
+        .into_iter()
+        .find(|(_, rect)| rect.contains(vec2(x, y)))
+        .map(|(id, _)| id)
+}
+
+async fn show_buffer_list(ctx: &mut Context) -> Option<usize> {
+    ctx.overlay_lines = ctx
+        .buffers
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            let (path, changed) = if i == ctx.active_idx {
+                (&ctx.active_buf, ctx.is_file_changed)
+            } else {
+                (&b.active_buf, b.is_file_changed)
+            };
+            format!("{}  {}", buffer_display_name(path, changed), path.display())
+        })
+        .collect();
+    ctx.overlay_selected = ctx.active_idx;
+    ctx.overlay_scroll = 0;
+    let res = loop {
+        if let Some(k) = input::get_last_key_pressed() {
+            match k {
+                KeyCode::Escape => break None,
+                KeyCode::Enter => break Some(ctx.overlay_selected),
+                KeyCode::Up => ctx.overlay_selected = ctx.overlay_selected.saturating_sub(1),
+                KeyCode::Down => {
+                    ctx.overlay_selected =
+                        std::cmp::min(ctx.overlay_selected + 1, ctx.overlay_lines.len() - 1)
+                }
+                _ => (),
+            }
+        }
+        render(ctx).await;
+    };
+    ctx.overlay_lines.clear();
+    res
+}
+
+// Every move through the list previews the theme, Escape goes back to the one
+// in use before.
+async fn show_theme_list(ctx: &mut Context) {
+    let original = ctx.theme.clone();
+    ctx.overlay_lines = theme_names();
+    ctx.overlay_selected = ctx
+        .overlay_lines
+        .iter()
+        .position(|n| *n == ctx.theme.name)
+        .unwrap_or(0);
+    ctx.overlay_scroll = 0;
+    loop {
+        if let Some(k) = input::get_last_key_pressed() {
+            let prev = ctx.overlay_selected;
+            match k {
+                KeyCode::Escape => {
+                    ctx.theme = original;
+                    ctx.status_msg = None;
+                    break;
+                }
+                KeyCode::Enter => break,
+                _ => move_overlay_selection(ctx, k, overlay_rows(ctx)),
+            }
+            if prev != ctx.overlay_selected {
+                match load_theme(&ctx.overlay_lines[ctx.overlay_selected]) {
+                    Ok(theme) => {
+                        ctx.theme = theme;
+                        ctx.status_msg = None;
+                    }
+                    Err(e) => ctx.status_msg = Some(e),
+                }
+            }
+        }
+        render(ctx).await;
+    }
+    ctx.overlay_lines.clear();
+}
+
+// Plain line diff: common head and tail are skipped, the changed middle is compared
+// line by line with an LCS table when it is small enough.
+fn line_diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let head = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let tail = old[head..]
+        .iter()
+        .rev()
+        .zip(new[head..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_mid, new_mid) = (&old[head..old.len() - tail], &new[head..new.len() - tail]);
+    let mut res = vec![format!("@@ -{} +{} @@", head + 1, head + 1)];
+    if old_mid.len() * new_mid.len() > 4_000_000 {
+        res.extend(old_mid.iter().map(|l| format!("- {l}")));
+        res.extend(new_mid.iter().map(|l| format!("+ {l}")));
+        return res;
+    }
+    let (n, m) = (old_mid.len(), new_mid.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old_mid[i] == new_mid[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old_mid[i] == new_mid[j] {
+            res.push(format!("  {}", old_mid[i]));
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            res.push(format!("+ {}", new_mid[j]));
+            j += 1;
+        } else {
+            res.push(format!("- {}", old_mid[i]));
+            i += 1;
+        }
+    }
+    res
+}
+
+async fn show_swap_diff(ctx: &mut Context, swap: &Path) {
+    let swapped = std::fs::read_to_string(swap).unwrap_or_default();
+    let original = std::fs::read_to_string(&ctx.buffer.name).unwrap_or_default();
+    ctx.overlay_lines = line_diff(&original, &swapped);
+    ctx.overlay_scroll = 0;
+    loop {
+        let page = screen_height() as usize / ctx.font_size as usize;
+        let max_scroll = ctx.overlay_lines.len().saturating_sub(1);
+        if let Some(k) = input::get_last_key_pressed() {
+            match k {
+                KeyCode::Escape => break,
+                KeyCode::Up => ctx.overlay_scroll = ctx.overlay_scroll.saturating_sub(1),
+                KeyCode::Down => {
+                    ctx.overlay_scroll = std::cmp::min(ctx.overlay_scroll + 1, max_scroll)
+                }
+                KeyCode::PageUp => ctx.overlay_scroll = ctx.overlay_scroll.saturating_sub(page),
+                KeyCode::PageDown => {
+                    ctx.overlay_scroll = std::cmp::min(ctx.overlay_scroll + page, max_scroll)
+                }
+                _ => (),
+            }
+        }
+        render(ctx).await;
+    }
+    ctx.overlay_lines.clear();
+    let _ = input::get_char_pressed();
+}
+
+pub async fn prompt_recover_swap(ctx: &mut Context, swap: &Path) {
+    loop {
+        if let Some(c) = input::get_char_pressed() {
+            if c == 'r' {
+                match ctx.buffer.read_swap(swap) {
+                    Ok(()) => ctx.is_file_changed = true,
+                    Err(e) => {
+                        ctx.status_msg = Some(format!("Error occured while reading swap file: {e}"))
+                    }
+                }
+                return;
+            } else if c == 'd' {
+                ctx.mode = Modes::ShowSwapDiff;
+                show_swap_diff(ctx, swap).await;
+                ctx.mode = Modes::RecoverPrompt;
+            } else if c == 'x' {
+                ctx.buffer.remove_swap();
+                return;
+            }
+        }
+        render(ctx).await;
+    }
+}
+
+fn write_swap_if_needed(ctx: &mut Context) {
+    if ctx.swap_interval == 0
+        || !ctx.is_swap_stale
+        || ctx.last_swap.elapsed().as_secs() < ctx.swap_interval
+    {
+        return;
+    }
+    ctx.last_swap = Instant::now();
+    ctx.is_swap_stale = false;
+    if let Err(e) = ctx.buffer.write_swap() {
+        eprintln!("Error occured while writing swap file: {e}");
+    }
+}
+
+fn move_cursor_up(ctx: &mut Context) {
+    let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    if ctx.is_soft_wrap {
+        // Moves by visual row, keeping the offset into the row.
+        let starts = line_wrap_starts(ctx, line);
+        let seg = wrap_segment(&starts, ctx.curr_cursor_pos.0);
+        let offset = ctx.curr_cursor_pos.0 - starts[seg];
+        if seg > 0 {
+            ctx.curr_cursor_pos.0 =
+                segment_col(&starts, seg - 1, offset, ctx.buffer.line_len(line));
+            update_view_buffer(ctx);
+            return;
+        }
+        if line == 0 {
+            return;
+        }
+        let prev = line_wrap_starts(ctx, line - 1);
+        ctx.curr_cursor_pos.0 =
+            segment_col(&prev, prev.len() - 1, offset, ctx.buffer.line_len(line - 1));
+    } else {
+        if line == 0 {
+            return;
+        }
+        ctx.curr_cursor_pos.0 = std::cmp::min(ctx.curr_cursor_pos.0, ctx.buffer.line_len(line - 1));
+    }
+    if ctx.curr_cursor_pos.1 == 0 {
+        ctx.vert_cell_count.0 -= 1;
+    } else {
+        ctx.curr_cursor_pos.1 -= 1;
+    }
+    update_view_buffer(ctx);
+}
+
+fn move_cursor_down(ctx: &mut Context) {
+    let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    if ctx.is_soft_wrap {
+        let starts = line_wrap_starts(ctx, line);
+        let seg = wrap_segment(&starts, ctx.curr_cursor_pos.0);
+        let offset = ctx.curr_cursor_pos.0 - starts[seg];
+        if seg + 1 < starts.len() {
+            ctx.curr_cursor_pos.0 =
+                segment_col(&starts, seg + 1, offset, ctx.buffer.line_len(line));
+        } else if line + 1 < ctx.buffer.line_count() {
+            let next = line_wrap_starts(ctx, line + 1);
+            ctx.curr_cursor_pos.0 = segment_col(&next, 0, offset, ctx.buffer.line_len(line + 1));
+            ctx.curr_cursor_pos.1 += 1;
+        }
+        // Scrolling is left to update_view_buffer, which counts wrapped rows.
+        update_view_buffer(ctx);
+        return;
+    }
+    if line + 1 >= ctx.buffer.line_count() {
+        return;
+    }
+    ctx.curr_cursor_pos.0 = std::cmp::min(ctx.curr_cursor_pos.0, ctx.buffer.line_len(line + 1));
+    if ctx.curr_cursor_pos.1 + 2 >= ctx.vert_cell_count.1 {
+        ctx.vert_cell_count.0 += 1;
+    } else {
+        ctx.curr_cursor_pos.1 += 1;
+    }
+    update_view_buffer(ctx);
+}
+
+fn move_cursor_left(ctx: &mut Context) {
+    if ctx.curr_cursor_pos.0 == 0 {
+        ()
+    } else {
+        ctx.curr_cursor_pos.0 -= 1
+    }
+    update_view_buffer(ctx);
+}
+
+fn move_cursor_right(ctx: &mut Context) {
+    let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
+    if ctx.buffer.char_at(off) == '\n' {
+        ()
+    } else {
+        ctx.curr_cursor_pos.0 += 1
+    }
+    update_view_buffer(ctx);
+}
+
+fn move_cursor_left_word(ctx: &mut Context) {
+    let row: Vec<char> = ctx
+        .buffer
+        .line(ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1)
+        .chars()
+        .collect();
+    ctx.curr_cursor_pos.0 = word_left_col(&row, ctx.curr_cursor_pos.0);
+    update_view_buffer(ctx);
+}
+
+fn move_cursor_right_word(ctx: &mut Context) {
+    let row: Vec<char> = ctx
+        .buffer
+        .line(ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1)
+        .chars()
+        .collect();
+    ctx.curr_cursor_pos.0 = word_right_col(&row, ctx.curr_cursor_pos.0);
+    update_view_buffer(ctx);
+}
+
+// Column of the closest space left of the column, the column itself if there is none.
+fn word_left_col(row: &[char], col: usize) -> usize {
+    row[..col].iter().rposition(|&c| c == ' ').unwrap_or(col)
+}
+
+// Column just past the next space at or right of the column.
+fn word_right_col(row: &[char], col: usize) -> usize {
+    row[col..]
+        .iter()
+        .position(|&c| c == ' ')
+        .map_or(col, |i| col + i + 1)
+}
+
+#[derive(Debug)]
+pub struct InternalBufOffset(Option<usize>, usize);
+impl InternalBufOffset {
+    pub fn get_inline_offset(&self) -> usize {
+        if self.0.is_some() {
+            self.1 - self.0.unwrap() - 1
+        } else {
+            self.1
+        }
+    }
+}
+
+fn get_cursor_pos_to_buf_offset(ctx: &Context) -> Option<InternalBufOffset> {
+    let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    if line == 0 {
+        return Some(InternalBufOffset(None, ctx.curr_cursor_pos.0));
+    }
+    if line >= ctx.buffer.line_count() {
+        return None;
+    }
+    let line_start = ctx.buffer.line_to_char(line);
+    Some(InternalBufOffset(
+        Some(line_start - 1),
+        line_start + ctx.curr_cursor_pos.0,
+    ))
+}
+
+pub fn get_ch_off_to_inline_off(ctx: &Context, off: usize) -> usize {
+    ctx.buffer.offset_to_line_col(off).1
+}
+
+fn delete_selection(ctx: &mut Context, record: &mut Record<Change>) -> String {
+    let Some((idx, deleted_str)) = take_selection(ctx) else {
+        return String::from("");
+    };
+    record.apply(ctx, Change::DeleteSelection(idx, deleted_str.clone()));
     update_view_buffer(ctx);
     deleted_str
 }
 
+// Clears the selection and returns the offset and text it covered, None when only
+// the trailing '\n' was selected.
+fn take_selection(ctx: &mut Context) -> Option<(usize, String)> {
+    let (start, end) = selection_offsets(ctx.selection_range.take()?);
+    if start == end && start == ctx.buffer.len_chars() - 1 {
+        return None;
+    }
+    if end == ctx.buffer.len_chars() - 1 {
+        ctx.buffer.insert_char(ctx.buffer.len_chars(), '\n');
+    }
+    Some((start, ctx.buffer.slice(start..end + 1)))
+}
+
+// Where an edit typed over the selection goes, the cursor when nothing was selected.
+fn edit_offset(ctx: &Context, deleted: &Option<(usize, String)>) -> usize {
+    match deleted {
+        Some((idx, _)) => *idx,
+        None => get_cursor_pos_to_buf_offset(ctx).unwrap().1,
+    }
+}
+
+// Applies an edit typed over the selection as one undo step with its deletion.
+fn apply_over_selection(
+    ctx: &mut Context,
+    record: &mut Record<Change>,
+    deleted: Option<(usize, String)>,
+    change: Change,
+) {
+    match deleted {
+        Some((idx, s)) => record.apply(
+            ctx,
+            Change::Group(vec![Change::DeleteSelection(idx, s), change]),
+        ),
+        None => record.apply(ctx, change),
+    }
+}
+
 fn delete_word(ctx: &mut Context, record: &mut Record<Change>) {
     let inter_buf_off = get_cursor_pos_to_buf_offset(ctx).unwrap();
-    let inline_offset = inter_buf_off.get_inline_offset();
-    let str;
-    if let Some(lfidx) = inter_buf_off.0 {
-        str = &ctx.buffer.buf[lfidx + 1..inter_buf_off.1];
-    } else {
-        str = &ctx.buffer.buf[..inter_buf_off.1];
-    }
-    if let Some(idx) = str.rfind(' ') {
+    let line_start = inter_buf_off.1 - inter_buf_off.get_inline_offset();
+    let str: Vec<char> = ctx
+        .buffer
+        .slice(line_start..inter_buf_off.1)
+        .chars()
+        .collect();
+    if let Some(idx) = str.iter().rposition(|&c| c == ' ') {
         record.apply(
             ctx,
-            Change::DeleteWord(
-                inter_buf_off.1 - (inline_offset - idx),
-                String::from_str(
-                    &ctx.buffer.buf[inter_buf_off.1 - (inline_offset - idx)..inter_buf_off.1],
-                )
-                .unwrap(),
-            ),
+            Change::DeleteWord(line_start + idx, str[idx..].iter().collect()),
         );
         ctx.curr_cursor_pos.0 = idx;
     }
     update_view_buffer(ctx);
 }
 
+// Every offset stored in a Change is a char index into the buffer, not a byte index.
 pub enum Change {
     DeleteWord(usize, String),
     DeleteSelection(usize, String),
     Delete(usize, char),
     Backspace(usize, char),
-    Enter(usize),
+    // (offset, indent inserted after the '\n')
+    Enter(usize, String),
     InsertLFAbove(usize),
     InsertLFBelow(usize),
     InsertChar(usize, char),
     Paste(usize, String),
     CutLine(usize, String),
+    // (from, to, per line endings of a mixed file, restored on undo)
+    ConvertLineEnding(LineEnding, LineEnding, Vec<bool>),
+    // (offset, old text, new text) in ascending order, offsets are from before the change.
+    Replace(Vec<(usize, String, String)>),
+    // Edits made by one keystroke, at every cursor or over the selection, applied in order.
+    Group(Vec<Change>),
 }
 
-fn get_view_pos_from_internal_off(ctx: &Context, off: usize) -> (usize, usize) {
-    let x = get_ch_off_to_inline_off(ctx, off);
-    if ctx.vert_cell_count.0 == 0 && ctx.curr_cursor_pos.1 == 0 {
-        return (x, 0);
+fn get_view_pos_from_internal_off(ctx: &mut Context, off: usize) -> (usize, usize) {
+    let (y, x) = ctx.buffer.offset_to_line_col(off);
+    if y < ctx.vert_cell_count.0 || y + 1 >= ctx.vert_cell_count.0 + ctx.vert_cell_count.1 {
+        ctx.vert_cell_count.0 = y.saturating_sub(ctx.vert_cell_count.1 / 2);
     }
-    let y = ctx
-        .buffer
-        .buf
-        .match_indices('\n')
-        .enumerate()
-        .find(|(_, l)| l.0 + x + 1 == off)
-        .unwrap()
-        .0
-        + 1;
-    println!("x: {x}, y: {y}");
-    (x, y % ctx.vert_cell_count.1)
+    (x, y - ctx.vert_cell_count.0)
 }
 
 impl undo::Action for Change {
@@ -566,187 +1858,768 @@ impl undo::Action for Change {
     type Output = ();
 
     fn apply(&mut self, target: &mut Self::Target) -> Self::Output {
+        target.is_swap_stale = true;
         match self {
             Change::DeleteWord(idx, s) => {
-                target.buffer.buf.replace_range(*idx..*idx + s.len(), "");
+                target.buffer.remove(*idx..*idx + s.chars().count());
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
             Change::Delete(idx, _) => {
-                target.buffer.buf.remove(*idx);
+                target.buffer.remove(*idx..*idx + 1);
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
             Change::Backspace(idx, _) => {
-                target.buffer.buf.remove(*idx);
+                target.buffer.remove(*idx..*idx + 1);
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
-            Change::Enter(idx) => {
-                target.buffer.buf.insert(*idx, '\n');
+            Change::Enter(idx, indent) => {
+                target.buffer.insert_char(*idx, '\n');
+                target.buffer.insert(*idx + 1, indent);
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
             Change::InsertChar(idx, c) => {
                 if *c == '\t' {
                     for _ in 0..target.tab_width {
-                        target.buffer.buf.insert(*idx, ' ');
+                        target.buffer.insert_char(*idx, ' ');
                     }
                 } else {
-                    target.buffer.buf.insert(*idx, *c);
+                    target.buffer.insert_char(*idx, *c);
                 }
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
             Change::Paste(idx, s) => {
-                target.buffer.buf.insert_str(*idx, s);
+                target.buffer.insert(*idx, s);
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
             Change::CutLine(idx, s) => {
-                target
-                    .buffer
-                    .buf
-                    .replace_range(*idx..=(*idx + s.len() - 1), "");
+                target.buffer.remove(*idx..*idx + s.chars().count());
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
+            Change::ConvertLineEnding(_, to, _) => {
+                target.buffer.set_line_ending(*to, Vec::new());
+            }
             Change::DeleteSelection(idx, s) => {
-                target
-                    .buffer
-                    .buf
-                    .replace_range(*idx..=(*idx + s.len() - 1), "");
+                target.buffer.remove(*idx..*idx + s.chars().count());
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
             Change::InsertLFAbove(idx) => {
-                target.buffer.buf.insert(*idx, '\n');
+                target.buffer.insert_char(*idx, '\n');
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
             Change::InsertLFBelow(idx) => {
-                target.buffer.buf.insert(*idx, '\n');
+                target.buffer.insert_char(*idx, '\n');
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
             }
+            Change::Replace(items) => {
+                // Back to front, so earlier offsets are not shifted by later replacements.
+                for (idx, old, new) in items.iter().rev() {
+                    target.buffer.remove(*idx..*idx + old.chars().count());
+                    target.buffer.insert(*idx, new);
+                }
+                target.curr_cursor_pos = get_view_pos_from_internal_off(target, items[0].0);
+            }
+            Change::Group(changes) => {
+                for change in changes.iter_mut() {
+                    undo::Action::apply(change, target);
+                }
+            }
         }
     }
 
     fn undo(&mut self, target: &mut Self::Target) -> Self::Output {
+        target.is_swap_stale = true;
         match self {
             Change::DeleteWord(idx, s) => {
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.insert_str(*idx, s);
+                target.buffer.insert(*idx, s);
             }
             Change::Delete(idx, c) => {
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.insert(*idx, *c);
+                target.buffer.insert_char(*idx, *c);
             }
             Change::Backspace(idx, c) => {
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.insert(*idx, *c);
+                target.buffer.insert_char(*idx, *c);
             }
-            Change::Enter(idx) => {
+            Change::Enter(idx, indent) => {
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.remove(*idx);
+                target
+                    .buffer
+                    .remove(*idx..*idx + 1 + indent.chars().count());
             }
             Change::InsertChar(idx, c) => {
                 target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
                 if *c == '\t' {
-                    for _ in 0..target.tab_width {
-                        target.buffer.buf.remove(*idx);
-                    }
+                    for _ in 0..target.tab_width {
+                        target.buffer.remove(*idx..*idx + 1);
+                    }
+                } else {
+                    target.buffer.remove(*idx..*idx + 1);
+                }
+            }
+            Change::Paste(idx, s) => {
+                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
+                target.buffer.remove(*idx..*idx + s.chars().count());
+            }
+            Change::CutLine(idx, s) => {
+                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
+                target.buffer.insert(*idx, s);
+            }
+            Change::ConvertLineEnding(from, _, crlf_lines) => {
+                target.buffer.set_line_ending(*from, crlf_lines.clone());
+            }
+            Change::DeleteSelection(idx, s) => {
+                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
+                target.buffer.insert(*idx, s);
+            }
+            Change::InsertLFAbove(idx) => {
+                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
+                target.buffer.remove(*idx..*idx + 1);
+            }
+            Change::InsertLFBelow(idx) => {
+                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
+                target.buffer.remove(*idx..*idx + 1);
+            }
+            Change::Replace(items) => {
+                // Front to back, the text before each item is already restored.
+                for (idx, old, new) in items.iter() {
+                    target.buffer.remove(*idx..*idx + new.chars().count());
+                    target.buffer.insert(*idx, old);
+                }
+                target.curr_cursor_pos = get_view_pos_from_internal_off(target, items[0].0);
+            }
+            Change::Group(changes) => {
+                for change in changes.iter_mut().rev() {
+                    undo::Action::undo(change, target);
+                }
+            }
+        }
+    }
+}
+
+fn get_curr_line(ctx: &Context) -> String {
+    ctx.buffer
+        .line(ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1)
+}
+
+// One indent level, a tab when the line is indented with tabs.
+fn indent_unit(tab_width: u8, indent: &str) -> String {
+    if indent.starts_with('\t') {
+        "\t".to_owned()
+    } else {
+        " ".repeat(tab_width as usize)
+    }
+}
+
+// Indent of the line Enter opens at the offset: the current line's, one level
+// deeper after an opening bracket or a trailing ':'.
+fn auto_indent(buffer: &Buffer, tab_width: u8, off: usize) -> String {
+    let (line, col) = buffer.offset_to_line_col(off);
+    let before: String = buffer.line(line).chars().take(col).collect();
+    let mut indent: String = before
+        .chars()
+        .take_while(|&c| c == ' ' || c == '\t')
+        .collect();
+    if before.trim_end().ends_with(['{', '(', '[', ':']) {
+        indent += &indent_unit(tab_width, &indent);
+    }
+    indent
+}
+
+// Replace items adding or dropping one indent level at the start of the lines
+// first..=last. Empty lines are not indented.
+fn shift_items(
+    buffer: &Buffer,
+    tab_width: u8,
+    first: usize,
+    last: usize,
+    is_outdent: bool,
+) -> Vec<(usize, String, String)> {
+    let mut items = Vec::new();
+    for line in first..=last {
+        let line_start = buffer.line_to_char(line);
+        let text: Vec<char> = buffer.line(line).chars().collect();
+        if is_outdent {
+            // One tab, or up to a level of spaces.
+            let n = if text.first() == Some(&'\t') {
+                1
+            } else {
+                text.iter()
+                    .take(tab_width as usize)
+                    .take_while(|&&c| c == ' ')
+                    .count()
+            };
+            if n > 0 {
+                items.push((line_start, text[..n].iter().collect(), String::new()));
+            }
+        } else if buffer.line_len(line) > 0 {
+            let indent: String = text
+                .iter()
+                .take_while(|&&c| c == ' ' || c == '\t')
+                .collect();
+            items.push((line_start, String::new(), indent_unit(tab_width, &indent)));
+        }
+    }
+    items
+}
+
+// Indents or outdents every line the selection touches, or the cursor line, as one
+// change and leaves those lines selected.
+fn shift_lines(ctx: &mut Context, record: &mut Record<Change>, is_outdent: bool) {
+    let (start, end) = match ctx.selection_range {
+        Some(sel) => selection_offsets(sel),
+        None => {
+            let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
+            (off, off)
+        }
+    };
+    let first = ctx.buffer.char_to_line(start);
+    let last = ctx.buffer.char_to_line(end);
+    let items = shift_items(&ctx.buffer, ctx.tab_width, first, last, is_outdent);
+    if items.is_empty() {
+        return;
+    }
+    ctx.is_file_changed = true;
+    let had_selection = ctx.selection_range.is_some();
+    let col = ctx.curr_cursor_pos.0 as isize;
+    let delta = items[0].2.chars().count() as isize - items[0].1.chars().count() as isize;
+    record.apply(ctx, Change::Replace(items));
+    if had_selection {
+        let first_start = ctx.buffer.line_to_char(first);
+        let last_end = ctx.buffer.line_to_char(last) + ctx.buffer.line_len(last);
+        ctx.curr_cursor_pos = get_view_pos_from_internal_off(ctx, last_end);
+        ctx.selection_range = Some((
+            selection_point(ctx, first_start),
+            selection_point(ctx, last_end),
+        ));
+    } else {
+        // The cursor keeps its place in the text of the line.
+        let line_start = ctx.buffer.line_to_char(first);
+        ctx.curr_cursor_pos = get_view_pos_from_internal_off(ctx, line_start);
+        ctx.curr_cursor_pos.0 = (col + delta).max(0) as usize;
+    }
+    update_view_buffer(ctx);
+}
+
+fn delete_curr_line(ctx: &mut Context, record: &mut Record<Change>) {
+    let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    let curr_line = get_curr_line(ctx);
+    if line + 1 < ctx.buffer.line_count() {
+        record.apply(
+            ctx,
+            Change::CutLine(ctx.buffer.line_to_char(line), curr_line),
+        );
+    }
+}
+
+fn clipboard_text(ctx: &mut Context) -> String {
+    // The buffer only ever holds '\n' line breaks, whatever the file's style.
+    ctx.clipboard.get_contents().unwrap().replace("\r\n", "\n")
+}
+
+fn is_in_gutter(ctx: &Context, x: f32) -> bool {
+    x < ctx.viewport.x + gutter_width(ctx, &ctx.buffer)
+}
+
+fn get_cell_under_cursor(ctx: &Context) -> &Cell {
+    let (x, y) = input::mouse_position();
+    let cell_y =
+        ((y - ctx.viewport.y + ctx.scroll_px).max(0f32) / ctx.font_size as f32).floor() as usize;
+    // Pointing into the gutter means the start of the line.
+    let x = if is_in_gutter(ctx, x) {
+        ctx.viewport.x + gutter_width(ctx, &ctx.buffer) + 1f32
+    } else {
+        x
+    };
+    let cell = ctx
+        .cells
+        .iter()
+        .filter(|c| c.row == cell_y)
+        .find(|c| c.coord.0 < x && x < (c.coord.0 + c.bound.0));
+    if let Some(c) = cell {
+        return c;
+    } else {
+        // Right of the text, the '\n' of the line or the last char of a wrapped row.
+        let cel = ctx.cells.iter().filter(|c| c.row == cell_y).last();
+        if cel.is_some() {
+            return cel.unwrap();
+        } else {
+            return ctx.cells.iter().last().unwrap();
+        }
+    }
+}
+
+// Selects the cursor line including its '\n' and leaves the cursor at its end.
+fn select_curr_line(ctx: &mut Context) {
+    let row = ctx.curr_cursor_pos.1;
+    let line = ctx.vert_cell_count.0 + row;
+    let start = ctx.buffer.line_to_char(line);
+    let len = ctx.buffer.line_len(line);
+    ctx.curr_cursor_pos = (len, row);
+    ctx.selection_range = Some(((start, (0, row)), (start + len, (len, row))));
+}
+
+// Inclusive range of the word, run of spaces or run of punctuation around the offset.
+fn word_range(ctx: &Context, off: usize) -> (usize, usize) {
+    let line = ctx.buffer.char_to_line(off);
+    let start = ctx.buffer.line_to_char(line);
+    let len = ctx.buffer.line_len(line);
+    if len == 0 {
+        return (off, off);
+    }
+    let chars: Vec<char> = ctx.buffer.line(line).chars().collect();
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    // Past the line end picks the last word on the line.
+    let col = (off - start).min(len - 1);
+    let kind = class(chars[col]);
+    let first = chars[..col]
+        .iter()
+        .rposition(|&c| class(c) != kind)
+        .map_or(0, |i| i + 1);
+    let last = chars[col..len]
+        .iter()
+        .position(|&c| class(c) != kind)
+        .map_or(len, |i| col + i)
+        - 1;
+    (start + first, start + last)
+}
+
+// Inclusive range of the line around the offset, '\n' included.
+fn line_range(ctx: &Context, off: usize) -> (usize, usize) {
+    let line = ctx.buffer.char_to_line(off);
+    let start = ctx.buffer.line_to_char(line);
+    (start, start + ctx.buffer.line_len(line))
+}
+
+fn selection_point(ctx: &Context, off: usize) -> (usize, (usize, usize)) {
+    let (line, col) = ctx.buffer.offset_to_line_col(off);
+    (off, (col, line.saturating_sub(ctx.vert_cell_count.0)))
+}
+
+// Selects from the double or triple clicked anchor to the word or line under the offset.
+fn extend_by_unit(ctx: &mut Context, anchor: (usize, usize), off: usize) {
+    let unit = if ctx.click_count == 3 {
+        line_range(ctx, off)
+    } else {
+        word_range(ctx, off)
+    };
+    let (from, to) = if off >= anchor.0 {
+        (anchor.0, anchor.1.max(unit.1))
+    } else {
+        (anchor.1, unit.0)
+    };
+    let end = selection_point(ctx, to);
+    ctx.curr_cursor_pos = end.1;
+    ctx.selection_range = Some((selection_point(ctx, from), end));
+}
+
+// What one keystroke does at every cursor.
+enum CursorEdit {
+    Char(char),
+    Backspace,
+    Delete,
+    Enter,
+    Paste(String),
+}
+
+fn selection_offsets(sel: SelectionRange) -> (usize, usize) {
+    (sel.0 .0.min(sel.1 .0), sel.0 .0.max(sel.1 .0))
+}
+
+// The main cursor first, then the extra ones.
+fn all_cursors(ctx: &Context) -> Vec<ExtraCursor> {
+    let mut cursors = vec![ExtraCursor {
+        off: get_cursor_pos_to_buf_offset(ctx).unwrap().1,
+        selection: ctx.selection_range.map(selection_offsets),
+    }];
+    cursors.extend(ctx.extra_cursors.iter().copied());
+    cursors
+}
+
+// Makes the first cursor the main one and keeps the others as extra cursors.
+fn set_cursors(ctx: &mut Context, mut cursors: Vec<ExtraCursor>) {
+    let main = cursors.remove(0);
+    ctx.curr_cursor_pos = get_view_pos_from_internal_off(ctx, main.off);
+    ctx.selection_range = main
+        .selection
+        .map(|(start, end)| (selection_point(ctx, start), selection_point(ctx, end)));
+    cursors.retain(|c| c.off != main.off);
+    cursors.sort_by_key(|c| c.off);
+    cursors.dedup_by_key(|c| c.off);
+    ctx.extra_cursors = cursors;
+}
+
+// Applies the edit at every cursor as one undoable change. Cursors are edited back
+// to front so the offsets taken before the edit stay valid.
+fn edit_at_cursors(ctx: &mut Context, record: &mut Record<Change>, edit: CursorEdit) {
+    let mut cursors: Vec<(usize, ExtraCursor)> = all_cursors(ctx).into_iter().enumerate().collect();
+    cursors.sort_by_key(|(_, c)| c.selection.map_or(c.off, |s| s.0));
+    // The final '\n' of the buffer is never removed.
+    let last = ctx.buffer.len_chars() - 1;
+    let mut groups = Vec::new();
+    // (cursor index, offset after the edit ignoring other cursors, chars added)
+    let mut moved: Vec<(usize, usize, isize)> = Vec::new();
+    let mut prev_end = None;
+    for (i, cursor) in cursors {
+        let (mut at, end) = cursor.selection.unwrap_or((cursor.off, cursor.off));
+        // Cursors running into the previous one are merged with it.
+        if prev_end.is_some_and(|p| at <= p) {
+            continue;
+        }
+        prev_end = Some(end);
+        let mut group = Vec::new();
+        let mut delta = 0isize;
+        if cursor.selection.is_some() && at < last {
+            let end = end.min(last - 1);
+            group.push(Change::DeleteSelection(at, ctx.buffer.slice(at..end + 1)));
+            delta -= (end + 1 - at) as isize;
+        }
+        let inserted = match &edit {
+            CursorEdit::Char(c) => {
+                group.push(Change::InsertChar(at, *c));
+                if *c == '\t' {
+                    ctx.tab_width as usize
                 } else {
-                    target.buffer.buf.remove(*idx);
+                    1
                 }
             }
-            Change::Paste(idx, s) => {
-                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target
-                    .buffer
-                    .buf
-                    .replace_range(*idx..=(*idx + s.len() - 1), "");
-            }
-            Change::CutLine(idx, s) => {
-                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.insert_str(*idx, s);
+            CursorEdit::Enter => {
+                let indent = auto_indent(&ctx.buffer, ctx.tab_width, at);
+                let inserted = 1 + indent.chars().count();
+                group.push(Change::Enter(at, indent));
+                inserted
             }
-            Change::DeleteSelection(idx, s) => {
-                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.insert_str(*idx, s);
+            CursorEdit::Paste(text) => {
+                group.push(Change::Paste(at, text.clone()));
+                text.chars().count()
             }
-            Change::InsertLFAbove(idx) => {
-                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.remove(*idx);
+            CursorEdit::Backspace if cursor.selection.is_none() && at > 0 => {
+                group.push(Change::Backspace(at - 1, ctx.buffer.char_at(at - 1)));
+                delta -= 1;
+                at -= 1;
+                0
             }
-            Change::InsertLFBelow(idx) => {
-                target.curr_cursor_pos = get_view_pos_from_internal_off(target, *idx);
-                target.buffer.buf.remove(*idx);
+            CursorEdit::Delete if cursor.selection.is_none() && at < last => {
+                group.push(Change::Delete(at, ctx.buffer.char_at(at)));
+                delta -= 1;
+                0
             }
-        }
+            _ => 0,
+        };
+        moved.push((i, at + inserted, delta + inserted as isize));
+        groups.push(group);
     }
+    let changes: Vec<Change> = groups.into_iter().rev().flatten().collect();
+    if !changes.is_empty() {
+        ctx.is_file_changed = true;
+        record.apply(ctx, Change::Group(changes));
+    }
+    let mut shift = 0isize;
+    let mut result = Vec::new();
+    for (i, off, delta) in moved {
+        result.push((i, (off as isize + shift) as usize));
+        shift += delta;
+    }
+    // The main cursor stays the main one unless it was merged away.
+    result.sort_by_key(|&(i, _)| i);
+    set_cursors(
+        ctx,
+        result
+            .into_iter()
+            .map(|(_, off)| ExtraCursor {
+                off,
+                selection: None,
+            })
+            .collect(),
+    );
+    update_view_buffer(ctx);
 }
 
-fn get_curr_line(ctx: &Context) -> String {
-    let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
-    let mut first_idx = off;
-    for i in (0..off).rev() {
-        if ctx.buffer.buf.chars().nth(i).unwrap() != '\n' {
-            first_idx -= 1;
-        } else {
-            break;
-        }
+// Moves every extra cursor to the column picked from its line and old column.
+fn move_extra_cursors(ctx: &mut Context, to_col: impl Fn(&[char], usize) -> usize) {
+    for i in 0..ctx.extra_cursors.len() {
+        let (line, col) = ctx.buffer.offset_to_line_col(ctx.extra_cursors[i].off);
+        let row: Vec<char> = ctx.buffer.line(line).chars().collect();
+        ctx.extra_cursors[i] = ExtraCursor {
+            off: ctx.buffer.line_to_char(line) + to_col(&row, col),
+            selection: None,
+        };
     }
-    let mut second_idx = off;
-    for i in off..ctx.buffer.buf.len() {
-        if ctx.buffer.buf.chars().nth(i).unwrap() != '\n' {
-            second_idx += 1;
-        } else {
-            break;
+    ctx.extra_cursors.sort_by_key(|c| c.off);
+    ctx.extra_cursors.dedup_by_key(|c| c.off);
+}
+
+// Selects the next occurrence of the selected text and keeps a cursor on the
+// current selection. Without a selection the word under the cursor is selected.
+fn add_next_occurrence(ctx: &mut Context) {
+    let Some(sel) = ctx.selection_range else {
+        let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
+        let (start, end) = word_range(ctx, off);
+        ctx.curr_cursor_pos = selection_point(ctx, end).1;
+        ctx.selection_range = Some((selection_point(ctx, start), selection_point(ctx, end)));
+        return;
+    };
+    let (start, end) = selection_offsets(sel);
+    let needle = ctx.buffer.slice(start..end + 1);
+    let taken: Vec<(usize, usize)> = all_cursors(ctx)
+        .iter()
+        .filter_map(|c| c.selection)
+        .collect();
+    let Some(found) = next_occurrence(&ctx.buffer, &needle, end + 1, &taken) else {
+        ctx.status_msg = Some("No more occurrences".to_owned());
+        return;
+    };
+    let mut cursors = all_cursors(ctx);
+    cursors.insert(
+        0,
+        ExtraCursor {
+            off: found + needle.chars().count() - 1,
+            selection: Some((found, found + needle.chars().count() - 1)),
+        },
+    );
+    set_cursors(ctx, cursors);
+    update_view_buffer(ctx);
+}
+
+// First occurrence at or after `from` that overlaps no selection yet, wrapping
+// around to the top of the buffer.
+fn next_occurrence(
+    buffer: &Buffer,
+    needle: &str,
+    from: usize,
+    taken: &[(usize, usize)],
+) -> Option<usize> {
+    let last = needle.chars().count().saturating_sub(1);
+    let mut found = None;
+    let mut wrapped = None;
+    buffer.for_each_occurrence(needle, &mut |off| {
+        // Occurrences overlap when the needle does, edits at every cursor need
+        // selections that do not.
+        if taken.iter().any(|&(a, b)| off <= b && a <= off + last) {
+            return true;
         }
+        if off >= from {
+            found = Some(off);
+            return false;
+        }
+        wrapped.get_or_insert(off);
+        true
+    });
+    found.or(wrapped)
+}
+
+// Replaces the selection with a cursor at the end of each line it touches.
+fn cursors_on_selected_lines(ctx: &mut Context) {
+    let Some(sel) = ctx.selection_range else {
+        return;
+    };
+    let (start, end) = selection_offsets(sel);
+    let first = ctx.buffer.char_to_line(start);
+    let last = ctx.buffer.char_to_line(end);
+    let cursors = (first..=last)
+        .rev()
+        .map(|line| ExtraCursor {
+            off: ctx.buffer.line_to_char(line) + ctx.buffer.line_len(line),
+            selection: None,
+        })
+        .collect();
+    set_cursors(ctx, cursors);
+    update_view_buffer(ctx);
+}
+
+fn block_bounds(block: BlockSelection) -> ((usize, usize), (usize, usize)) {
+    let ((l0, c0), (l1, c1)) = block;
+    ((l0.min(l1), l0.max(l1)), (c0.min(c1), c0.max(c1)))
+}
+
+// Offsets of the part of each block line that holds text, the end is exclusive.
+fn block_rows(buffer: &Buffer, block: BlockSelection) -> Vec<(usize, usize)> {
+    let ((top, bottom), (left, right)) = block_bounds(block);
+    (top..=bottom)
+        .map(|line| {
+            let start = buffer.line_to_char(line);
+            let len = buffer.line_len(line);
+            (start + left.min(len), start + right.min(len))
+        })
+        .collect()
+}
+
+fn block_text(buffer: &Buffer, block: BlockSelection) -> String {
+    block_rows(buffer, block)
+        .iter()
+        .map(|&(start, end)| buffer.slice(start..end))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// Moves the block's corner, starting a block at the cursor if there is none.
+fn move_block_corner(ctx: &mut Context, lines: isize, cols: isize) {
+    let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    let at = (line, ctx.curr_cursor_pos.0);
+    let (anchor, corner) = ctx.block_selection.unwrap_or((at, at));
+    let last = ctx.buffer.line_count().saturating_sub(1) as isize;
+    let corner = (
+        (corner.0 as isize + lines).clamp(0, last) as usize,
+        (corner.1 as isize + cols).max(0) as usize,
+    );
+    ctx.selection_range = None;
+    ctx.block_selection = Some((anchor, corner));
+    let off = ctx.buffer.line_to_char(corner.0) + corner.1.min(ctx.buffer.line_len(corner.0));
+    ctx.curr_cursor_pos = get_view_pos_from_internal_off(ctx, off);
+    update_view_buffer(ctx);
+}
+
+// Line and column under the pointer, counting columns past the end of the line.
+fn block_corner_under_pointer(ctx: &Context) -> (usize, usize) {
+    let x = input::mouse_position().0;
+    let cell = get_cell_under_cursor(ctx);
+    let mut col = cell.pos.0;
+    if cell.c == '\n' && x > cell.coord.0 {
+        col += ((x - cell.coord.0) / column_width(ctx)) as usize;
     }
-    String::from(&ctx.buffer.buf[first_idx..=second_idx])
+    (ctx.vert_cell_count.0 + cell.pos.1, col)
 }
 
-fn delete_curr_line(ctx: &mut Context, record: &mut Record<Change>) {
-    let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
-    let mut first_idx = off;
-    for i in (0..off).rev() {
-        if ctx.buffer.buf.chars().nth(i).unwrap() != '\n' {
-            first_idx -= 1;
-        } else {
+// Alt-drag selects the rectangle between the cursor and the pointer.
+async fn drag_block_selection(ctx: &mut Context) {
+    let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    let anchor = ctx
+        .block_selection
+        .map_or((line, ctx.curr_cursor_pos.0), |b| b.0);
+    ctx.selection_range = None;
+    loop {
+        let corner = block_corner_under_pointer(ctx);
+        ctx.block_selection = Some((anchor, corner));
+        ctx.curr_cursor_pos = (
+            corner.1.min(ctx.buffer.line_len(corner.0)),
+            corner.0 - ctx.vert_cell_count.0,
+        );
+        if input::is_mouse_button_released(MouseButton::Left) {
+            ctx.timer = None;
             break;
         }
-    }
-    let curr_line = get_curr_line(ctx);
-    if ctx
-        .buffer
-        .buf
-        .chars()
-        .nth(first_idx + curr_line.len() + 1)
-        .is_some()
-    {
-        record.apply(ctx, Change::CutLine(first_idx, curr_line));
+        render(ctx).await;
     }
 }
 
-fn paste(ctx: &mut Context, record: &mut Record<Change>) {
-    let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
-    let clipboard_text = ctx.clipboard.get_contents().unwrap();
-    record.apply(ctx, Change::Paste(off, clipboard_text));
+// Typing into a block goes through a cursor on each of its lines.
+fn block_to_cursors(ctx: &mut Context, block: BlockSelection) {
+    let cursors = block_rows(&ctx.buffer, block)
+        .into_iter()
+        .map(|(start, end)| {
+            if start < end {
+                ExtraCursor {
+                    off: end - 1,
+                    selection: Some((start, end - 1)),
+                }
+            } else {
+                ExtraCursor {
+                    off: start,
+                    selection: None,
+                }
+            }
+        })
+        .collect();
+    set_cursors(ctx, cursors);
 }
 
-fn get_cell_under_cursor(ctx: &Context) -> &Cell {
+// Replaces the block's part of each line with the row of the same index, or without
+// a block inserts the rows one per line from the cursor. Lines shorter than the
+// column are padded with spaces. Rows past the end of the buffer go on new lines.
+fn put_column(
+    ctx: &mut Context,
+    record: &mut Record<Change>,
+    block: Option<BlockSelection>,
+    rows: &[&str],
+) {
+    let cursor_line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+    let ((top, bottom), (left, right)) = match block {
+        Some(block) => block_bounds(block),
+        None => (
+            (cursor_line, cursor_line),
+            (ctx.curr_cursor_pos.0, ctx.curr_cursor_pos.0),
+        ),
+    };
+    let block_lines = if block.is_some() { bottom - top + 1 } else { 0 };
+    let mut groups = Vec::new();
+    for i in 0..block_lines.max(rows.len()) {
+        let line = top + i;
+        if line >= ctx.buffer.line_count() {
+            // Appended at the end; the groups are applied in reverse, so each one lands
+            // in front of the rows below it.
+            let row = rows.get(i).map_or("", |r| *r);
+            let pad = if row.is_empty() {
+                String::new()
+            } else {
+                " ".repeat(left)
+            };
+            let end = ctx.buffer.len_chars();
+            groups.push(vec![Change::Paste(end, pad + row + "\n")]);
+            continue;
+        }
+        let start = ctx.buffer.line_to_char(line);
+        let len = ctx.buffer.line_len(line);
+        let at = start + left.min(len);
+        let mut group = Vec::new();
+        if i < block_lines && left < len {
+            let end = start + right.min(len);
+            group.push(Change::DeleteSelection(at, ctx.buffer.slice(at..end)));
+        }
+        if let Some(row) = rows.get(i).filter(|r| !r.is_empty()) {
+            let pad = " ".repeat(left.saturating_sub(len));
+            group.push(Change::Paste(at, pad + row));
+        }
+        groups.push(group);
+    }
+    // Bottom line first, so the offsets of the lines above stay valid.
+    let changes: Vec<Change> = groups.into_iter().rev().flatten().collect();
+    if !changes.is_empty() {
+        ctx.is_file_changed = true;
+        record.apply(ctx, Change::Group(changes));
+    }
+    let col = left + rows.first().map_or(0, |r| r.chars().count());
+    let off = ctx.buffer.line_to_char(top) + col.min(ctx.buffer.line_len(top));
+    ctx.curr_cursor_pos = get_view_pos_from_internal_off(ctx, off);
+    update_view_buffer(ctx);
+}
+
+fn get_tab_under_cursor(ctx: &Context) -> Option<usize> {
     let (x, y) = input::mouse_position();
-    let cell_y = (y / ctx.font_size as f32).floor() as usize;
-    let cell = ctx
-        .cells
+    if y >= tab_bar_height(ctx) {
+        return None;
+    }
+    tab_layout(ctx)
         .iter()
-        .filter(|c| c.pos.1 == cell_y)
-        .find(|c| c.coord.0 < x && x < (c.coord.0 + c.bound.0));
-    if let Some(c) = cell {
-        return c;
-    } else {
-        let cel = ctx
-            .cells
-            .iter()
-            .filter(|c| c.pos.1 == cell_y)
-            .find(|c| c.c == '\n');
-        if cel.is_some() {
-            return cel.unwrap();
-        } else {
-            return ctx.cells.iter().last().unwrap();
+        .position(|(tab_x, tab_w, _)| *tab_x <= x && x < tab_x + tab_w)
+}
+
+// Moves the active tab along with the pointer until the left button is released.
+async fn drag_tab(ctx: &mut Context) {
+    loop {
+        if input::is_mouse_button_released(MouseButton::Left)
+            || !input::is_mouse_button_down(MouseButton::Left)
+        {
+            break;
+        }
+        if let Some(idx) = get_tab_under_cursor(ctx) {
+            while ctx.active_idx < idx {
+                swap_buffer_slots(ctx, ctx.active_idx, ctx.active_idx + 1);
+                ctx.active_idx += 1;
+            }
+            while ctx.active_idx > idx {
+                swap_buffer_slots(ctx, ctx.active_idx, ctx.active_idx - 1);
+                ctx.active_idx -= 1;
+            }
         }
+        render(ctx).await;
     }
 }
 
@@ -755,7 +2628,60 @@ pub async fn update_state(
     record: &mut Record<Change>,
     bell: &macroquad::audio::Sound,
 ) {
-    match get_command() {
+    write_swap_if_needed(ctx);
+    step_wheel_scroll(ctx);
+    let command = get_command();
+    if command.is_some() {
+        ctx.status_msg = None;
+    }
+    if is_key_down(KeyCode::LeftAlt) {
+        // Alt shortcuts still queue their character, drop it so it is not typed later.
+        while input::get_char_pressed().is_some() {}
+    }
+    // Only commands that act on every cursor keep the extra ones.
+    if !matches!(
+        command,
+        None | Some(
+            Command::CharPressed(_)
+                | Command::Backspace
+                | Command::Delete
+                | Command::Enter
+                | Command::Paste
+                | Command::WordMoveLeft
+                | Command::WordMoveRight
+                | Command::MoveLeft
+                | Command::MoveRight
+                | Command::MouseLeftClick
+                | Command::MouseDown
+                | Command::ScrollVertical(_)
+                | Command::ScrollHorizontal(_)
+                | Command::AddNextOccurrence
+                | Command::CursorsOnLines
+        )
+    ) {
+        ctx.extra_cursors.clear();
+    }
+    if !matches!(
+        command,
+        None | Some(
+            Command::CharPressed(_)
+                | Command::Backspace
+                | Command::Delete
+                | Command::Copy
+                | Command::Cut
+                | Command::Paste
+                | Command::MouseDown
+                | Command::ScrollVertical(_)
+                | Command::ScrollHorizontal(_)
+                | Command::BlockSelectLeft
+                | Command::BlockSelectRight
+                | Command::BlockSelectUp
+                | Command::BlockSelectDown
+        )
+    ) {
+        ctx.block_selection = None;
+    }
+    match command {
         Some(Command::InsertLFAbove) => {
             if ctx.mode == Modes::Edit {
                 ctx.is_file_changed = true;
@@ -769,12 +2695,10 @@ pub async fn update_state(
         Some(Command::InsertLFBelow) => {
             if ctx.mode == Modes::Edit {
                 ctx.is_file_changed = true;
-                let mut inter_buf_off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
-                while ctx.buffer.buf.chars().nth(inter_buf_off).unwrap() != '\n' {
-                    inter_buf_off += 1;
-                }
-                record.apply(ctx, Change::InsertLFBelow(inter_buf_off + 1));
-                ctx.curr_cursor_pos = (0, ctx.curr_cursor_pos.1 + 1);
+                let next_line = ctx
+                    .buffer
+                    .line_to_char(ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1 + 1);
+                record.apply(ctx, Change::InsertLFBelow(next_line));
             }
             update_view_buffer(ctx);
         }
@@ -838,11 +2762,23 @@ pub async fn update_state(
                 (curr_pos, ctx.curr_cursor_pos),
             ));
         }
+        Some(Command::BlockSelectLeft) => move_block_corner(ctx, 0, -1),
+        Some(Command::BlockSelectRight) => move_block_corner(ctx, 0, 1),
+        Some(Command::BlockSelectUp) => move_block_corner(ctx, -1, 0),
+        Some(Command::BlockSelectDown) => move_block_corner(ctx, 1, 0),
         Some(Command::ShiftPageUp) => {
-            todo!() // TODO
+            let page = wrap_width(ctx, &ctx.buffer, ctx.viewport);
+            scroll_horizontally(ctx, -page);
         }
         Some(Command::ShiftPageDown) => {
-            todo!() // TODO
+            let page = wrap_width(ctx, &ctx.buffer, ctx.viewport);
+            scroll_horizontally(ctx, page);
+        }
+        Some(Command::ScrollVertical(delta)) => {
+            ctx.scroll_pending -= delta * ctx.scroll_lines as f32 * ctx.font_size as f32;
+        }
+        Some(Command::ScrollHorizontal(delta)) => {
+            scroll_horizontally(ctx, -delta * WHEEL_COLUMNS * column_width(ctx));
         }
         Some(Command::Undo) => {
             if !record.can_undo() {
@@ -861,17 +2797,27 @@ pub async fn update_state(
             update_view_buffer(ctx);
         }
         Some(Command::Copy) => {
+            if let Some(block) = ctx.block_selection.take() {
+                let text = block_text(&ctx.buffer, block);
+                ctx.clipboard
+                    .set_contents(text.clone())
+                    .expect("Failed when copying text to system clipboard!");
+                ctx.block_clip = Some(text);
+                return;
+            }
             if ctx.selection_range.is_some() {
                 if ctx.selection_range.unwrap().0 .0 < ctx.selection_range.unwrap().1 .0 {
-                    let str = &ctx.buffer.buf
-                        [ctx.selection_range.unwrap().0 .0..=ctx.selection_range.unwrap().1 .0];
+                    let str = ctx.buffer.slice(
+                        ctx.selection_range.unwrap().0 .0..ctx.selection_range.unwrap().1 .0 + 1,
+                    );
                     ctx.clipboard
                         .set_contents(str.to_owned())
                         .expect("Failed when copying text to system clipboard!");
                     dbg!(&str);
                 } else {
-                    let str = &ctx.buffer.buf
-                        [ctx.selection_range.unwrap().1 .0..=ctx.selection_range.unwrap().0 .0];
+                    let str = ctx.buffer.slice(
+                        ctx.selection_range.unwrap().1 .0..ctx.selection_range.unwrap().0 .0 + 1,
+                    );
                     ctx.clipboard
                         .set_contents(str.to_owned())
                         .expect("Failed when copying text to system clipboard!");
@@ -887,6 +2833,15 @@ pub async fn update_state(
             ctx.selection_range = None;
         }
         Some(Command::Cut) => {
+            if let Some(block) = ctx.block_selection.take() {
+                let text = block_text(&ctx.buffer, block);
+                ctx.clipboard
+                    .set_contents(text.clone())
+                    .expect("Failed when cutted text copied to system clipboard!");
+                ctx.block_clip = Some(text);
+                put_column(ctx, record, Some(block), &[]);
+                return;
+            }
             ctx.is_file_changed = true;
             if ctx.selection_range.is_some() {
                 let deleted_str = delete_selection(ctx, record);
@@ -904,11 +2859,23 @@ pub async fn update_state(
             update_view_buffer(ctx);
         }
         Some(Command::Paste) => {
-            ctx.is_file_changed = true;
-            if ctx.selection_range.is_some() {
-                delete_selection(ctx, record);
+            let text = clipboard_text(ctx);
+            if ctx.block_clip.as_deref() == Some(text.as_str()) {
+                let block = ctx.block_selection.take();
+                put_column(ctx, record, block, &text.split('\n').collect::<Vec<&str>>());
+                return;
+            }
+            if let Some(block) = ctx.block_selection.take() {
+                block_to_cursors(ctx, block);
+            }
+            if !ctx.extra_cursors.is_empty() {
+                edit_at_cursors(ctx, record, CursorEdit::Paste(text));
+                return;
             }
-            paste(ctx, record);
+            ctx.is_file_changed = true;
+            let deleted = take_selection(ctx);
+            let off = edit_offset(ctx, &deleted);
+            apply_over_selection(ctx, record, deleted, Change::Paste(off, text));
             update_view_buffer(ctx);
         }
         Some(Command::OpenDocument) => {
@@ -918,28 +2885,132 @@ pub async fn update_state(
                 .set_directory("/")
                 .pick_file()
             {
-                ctx.buffer.read_to_buffer(&file);
-                ctx.active_buf = file.to_owned();
-                update_view_buffer(ctx);
+                open_buffer(ctx, record, &file).await;
             } else {
                 eprintln!("Invalid file selected!");
             }
         }
+        Some(Command::QuickOpen) => {
+            ctx.mode = Modes::QuickOpen;
+            let picked = quick_open(ctx).await;
+            ctx.prompt_input.clear();
+            ctx.mode = Modes::Edit;
+            if let Some(file) = picked {
+                open_buffer(ctx, record, &file).await;
+            }
+        }
+        Some(Command::NextBuffer) => {
+            let next = (ctx.active_idx + 1) % ctx.buffers.len();
+            switch_buffer(ctx, record, next);
+        }
+        Some(Command::PrevBuffer) => {
+            let prev = (ctx.active_idx + ctx.buffers.len() - 1) % ctx.buffers.len();
+            switch_buffer(ctx, record, prev);
+        }
+        Some(Command::BufferList) => {
+            ctx.mode = Modes::BufferList;
+            let selected = show_buffer_list(ctx).await;
+            ctx.mode = Modes::Edit;
+            if let Some(idx) = selected {
+                switch_buffer(ctx, record, idx);
+            }
+        }
+        Some(Command::ToggleSoftWrap) => {
+            ctx.is_soft_wrap = !ctx.is_soft_wrap;
+            ctx.status_msg = Some(format!(
+                "Soft wrap {}",
+                if ctx.is_soft_wrap { "on" } else { "off" }
+            ));
+            update_view_buffer(ctx);
+        }
+        Some(Command::SwitchTheme) => {
+            ctx.mode = Modes::ThemeList;
+            show_theme_list(ctx).await;
+            ctx.mode = Modes::Edit;
+            update_view_buffer(ctx);
+        }
+        Some(Command::CloseBuffer) => {
+            close_active_buffer(ctx, record, bell).await;
+        }
+        Some(Command::SplitVertical) => {
+            split_active_pane(ctx, SplitDir::Vertical);
+        }
+        Some(Command::SplitHorizontal) => {
+            split_active_pane(ctx, SplitDir::Horizontal);
+        }
+        Some(Command::ClosePane) => {
+            close_active_pane(ctx, record, bell);
+        }
+        Some(Command::FocusPaneLeft) => {
+            if let Some(id) = neighbour_pane(ctx, -1f32, 0f32) {
+                focus_pane(ctx, record, id);
+            }
+        }
+        Some(Command::FocusPaneRight) => {
+            if let Some(id) = neighbour_pane(ctx, 1f32, 0f32) {
+                focus_pane(ctx, record, id);
+            }
+        }
+        Some(Command::FocusPaneUp) => {
+            if let Some(id) = neighbour_pane(ctx, 0f32, -1f32) {
+                focus_pane(ctx, record, id);
+            }
+        }
+        Some(Command::FocusPaneDown) => {
+            if let Some(id) = neighbour_pane(ctx, 0f32, 1f32) {
+                focus_pane(ctx, record, id);
+            }
+        }
+        Some(Command::GrowPane) if ctx.layout.resize_pane(ctx.active_pane, 0.05) => {
+            update_view_buffer(ctx);
+            keep_cursor_in_view(ctx);
+        }
+        Some(Command::ShrinkPane) if ctx.layout.resize_pane(ctx.active_pane, -0.05) => {
+            update_view_buffer(ctx);
+            keep_cursor_in_view(ctx);
+        }
         Some(Command::Exit) => {
-            if !ctx.is_file_changed {
+            let is_any_changed =
+                ctx.is_file_changed || ctx.buffers.iter().any(|b| b.is_file_changed);
+            if !is_any_changed {
                 ctx.is_exit = true
             } else {
                 macroquad::audio::play_sound_once(*bell);
                 ctx.mode = Modes::ModifiedPrompt;
-                prompt_unsaved_changes(ctx).await;
+                ctx.is_exit = prompt_unsaved_changes(ctx).await;
                 ctx.mode = Modes::Edit;
             }
+            if ctx.is_exit {
+                ctx.buffer.remove_swap();
+                for (i, b) in ctx.buffers.iter().enumerate() {
+                    if i != ctx.active_idx {
+                        b.buffer.remove_swap();
+                    }
+                }
+            }
         }
-        Some(Command::Save) => {
-            if ctx.is_file_changed {
-                ctx.buffer.write_to_file();
+        Some(Command::Save) if ctx.is_file_changed => match ctx.buffer.write_to_file(ctx.backup) {
+            Ok(()) => {
+                ctx.is_file_changed = false;
+                ctx.is_swap_stale = false;
+                ctx.buffer.remove_swap();
             }
-            ctx.is_file_changed = false;
+            Err(e) => {
+                macroquad::audio::play_sound_once(*bell);
+                ctx.status_msg = Some(format!("Error occured while saving file: {e}"));
+            }
+        },
+        Some(Command::ConvertLineEnding) => {
+            let from = ctx.buffer.line_ending;
+            let to = if from == LineEnding::Lf {
+                LineEnding::Crlf
+            } else {
+                LineEnding::Lf
+            };
+            let crlf_lines = ctx.buffer.crlf_lines();
+            ctx.is_file_changed = true;
+            record.apply(ctx, Change::ConvertLineEnding(from, to, crlf_lines));
+            update_view_buffer(ctx);
         }
         Some(Command::Help) => {
             ctx.mode = Modes::ShowHelp;
@@ -948,26 +3019,43 @@ pub async fn update_state(
         }
         Some(Command::FindInCaseSensitive) => {
             ctx.mode = Modes::FindCaseInSensitive;
-            let search_pos = find_in_buf(ctx, false).await;
-            ctx.vert_cell_count.0 = search_pos.1;
-            ctx.curr_cursor_pos = (search_pos.0, 0);
+            find_in_buf(ctx, false).await;
             update_view_buffer(ctx);
             ctx.prompt_input.clear();
             ctx.mode = Modes::Edit;
         }
         Some(Command::FindCaseSensitive) => {
             ctx.mode = Modes::FindCaseSensitive;
-            let search_pos = find_in_buf(ctx, true).await;
-            ctx.vert_cell_count.0 = search_pos.1;
-            ctx.curr_cursor_pos = (search_pos.0, 0);
+            find_in_buf(ctx, true).await;
+            update_view_buffer(ctx);
+            ctx.prompt_input.clear();
+            ctx.mode = Modes::Edit;
+        }
+        Some(Command::ReplaceInCaseSensitive) => {
+            ctx.mode = Modes::ReplaceCaseInSensitive;
+            replace_in_buf(ctx, record, false).await;
+            update_view_buffer(ctx);
+            ctx.mode = Modes::Edit;
+        }
+        Some(Command::ReplaceCaseSensitive) => {
+            ctx.mode = Modes::ReplaceCaseSensitive;
+            replace_in_buf(ctx, record, true).await;
             update_view_buffer(ctx);
+            ctx.mode = Modes::Edit;
+        }
+        Some(Command::FindInFiles) => {
+            ctx.mode = Modes::FindInFiles;
+            let selected = find_in_files(ctx).await;
             ctx.prompt_input.clear();
             ctx.mode = Modes::Edit;
+            if let Some(idx) = selected {
+                open_file_match(ctx, record, idx).await;
+            }
         }
         Some(Command::GoToLine) => {
             ctx.mode = Modes::GoToLine;
             let line = go_to_line(ctx).await;
-            let line = std::cmp::max(std::cmp::min(ctx.buffer.buf.lines().count(), line), 1);
+            let line = std::cmp::max(std::cmp::min(ctx.buffer.line_count(), line), 1);
             ctx.vert_cell_count.0 = line - 1;
             ctx.curr_cursor_pos = (0, 0);
             update_view_buffer(ctx);
@@ -979,6 +3067,10 @@ pub async fn update_state(
                 ctx.timer = Some(Instant::now());
             }
             if ctx.timer.unwrap().elapsed().as_millis() > 100 {
+                if is_key_down(KeyCode::LeftAlt) {
+                    drag_block_selection(ctx).await;
+                    return;
+                }
                 if ctx.selection_range.is_none() {
                     let init_pos = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
                     ctx.selection_range = Some((
@@ -989,6 +3081,21 @@ pub async fn update_state(
                 loop {
                     let cell = get_cell_under_cursor(ctx);
                     ctx.curr_cursor_pos = cell.pos;
+                    if let Some(anchor) = ctx.click_anchor {
+                        let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
+                        extend_by_unit(ctx, anchor, off);
+                        if input::is_mouse_button_released(MouseButton::Left) {
+                            ctx.timer = None;
+                            break;
+                        }
+                        render(ctx).await;
+                        continue;
+                    }
+                    // Dragging down the gutter selects whole lines.
+                    if is_in_gutter(ctx, input::mouse_position().0) {
+                        let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+                        ctx.curr_cursor_pos.0 = ctx.buffer.line_len(line);
+                    }
                     ctx.selection_range = Some((
                         ctx.selection_range.unwrap().0,
                         (
@@ -1011,28 +3118,81 @@ pub async fn update_state(
             }
         }
         Some(Command::MouseLeftClick) => {
+            if input::mouse_position().1 < tab_bar_height(ctx) {
+                if let Some(idx) = get_tab_under_cursor(ctx) {
+                    switch_buffer(ctx, record, idx);
+                    drag_tab(ctx).await;
+                }
+                return;
+            }
+            if let Some(id) = pane_under_cursor(ctx) {
+                focus_pane(ctx, record, id);
+            }
+            let pos = get_cell_under_cursor(ctx).pos;
+            ctx.timer = None;
+            ctx.click_anchor = None;
+            if is_key_down(KeyCode::LeftControl) && !is_in_gutter(ctx, input::mouse_position().0) {
+                // Ctrl-click adds a cursor, or drops the extra cursor under the pointer.
+                let main = all_cursors(ctx)[0];
+                let prev_pos = ctx.curr_cursor_pos;
+                ctx.curr_cursor_pos = pos;
+                let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
+                if let Some(i) = ctx.extra_cursors.iter().position(|c| c.off == off) {
+                    ctx.extra_cursors.remove(i);
+                    ctx.curr_cursor_pos = prev_pos;
+                } else if main.off != off {
+                    ctx.extra_cursors.push(main);
+                    ctx.selection_range = None;
+                }
+                ctx.last_click = None;
+                return;
+            }
+            ctx.extra_cursors.clear();
+            if is_key_down(KeyCode::LeftShift) {
+                // Shift-click keeps the selection start and moves its end.
+                let anchor = match ctx.selection_range {
+                    Some(sel) => sel.0,
+                    None => (
+                        get_cursor_pos_to_buf_offset(ctx).unwrap().1,
+                        ctx.curr_cursor_pos,
+                    ),
+                };
+                ctx.curr_cursor_pos = pos;
+                let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
+                ctx.selection_range = (anchor.0 != off).then_some((anchor, (off, pos)));
+                ctx.last_click = None;
+                return;
+            }
             ctx.selection_range = None;
-            let (x, y) = input::mouse_position();
-            let cell_y = (y / ctx.font_size as f32).floor() as usize;
-            let cell = ctx
-                .cells
-                .iter()
-                .filter(|c| c.pos.1 == cell_y)
-                .find(|c| c.coord.0 < x && x < (c.coord.0 + c.bound.0));
-            if let Some(c) = cell {
-                ctx.curr_cursor_pos.0 = c.pos.0;
-                ctx.curr_cursor_pos.1 = cell_y;
-            } else {
-                let cel = ctx
-                    .cells
-                    .iter()
-                    .filter(|c| c.pos.1 == cell_y)
-                    .find(|c| c.c == '\n');
-                if cel.is_some() {
-                    ctx.curr_cursor_pos = (cel.unwrap().pos.0, cell_y);
-                } else {
-                    ctx.curr_cursor_pos = ctx.cells.iter().last().unwrap().pos;
+            ctx.curr_cursor_pos = pos;
+            if is_in_gutter(ctx, input::mouse_position().0) {
+                select_curr_line(ctx);
+                ctx.last_click = None;
+                return;
+            }
+            let off = get_cursor_pos_to_buf_offset(ctx).unwrap().1;
+            ctx.click_count = match ctx.last_click {
+                Some((time, last_off))
+                    if last_off == off && time.elapsed().as_millis() < MULTI_CLICK_MS =>
+                {
+                    ctx.click_count % 3 + 1
                 }
+                _ => 1,
+            };
+            ctx.last_click = Some((Instant::now(), off));
+            match ctx.click_count {
+                2 => ctx.click_anchor = Some(word_range(ctx, off)),
+                3 => ctx.click_anchor = Some(line_range(ctx, off)),
+                _ => {}
+            }
+            if let Some(anchor) = ctx.click_anchor {
+                extend_by_unit(ctx, anchor, anchor.0);
+            }
+        }
+        Some(Command::MouseMiddleClick) => {
+            if let Some(idx) = get_tab_under_cursor(ctx) {
+                switch_buffer(ctx, record, idx);
+                close_active_buffer(ctx, record, bell).await;
             }
         }
         Some(Command::GoTop) => {
@@ -1043,30 +3203,53 @@ pub async fn update_state(
         }
         Some(Command::GoBottom) => {
             ctx.selection_range = None;
-            ctx.vert_cell_count.0 = ctx.buffer.buf.lines().count() - 1;
+            ctx.vert_cell_count.0 = ctx.buffer.line_count() - 1;
             ctx.curr_cursor_pos = (0, 0);
             update_view_buffer(ctx);
         }
         Some(Command::WordMoveRight) => {
             ctx.selection_range = None;
+            move_extra_cursors(ctx, word_right_col);
             move_cursor_right_word(ctx);
         }
         Some(Command::WordMoveLeft) => {
             ctx.selection_range = None;
+            move_extra_cursors(ctx, word_left_col);
             move_cursor_left_word(ctx);
         }
+        Some(Command::Outdent) => {
+            // Shift-Tab still queues a tab character, it must not be typed.
+            while input::get_char_pressed().is_some() {}
+            shift_lines(ctx, record, true);
+        }
+        Some(Command::AddNextOccurrence) => add_next_occurrence(ctx),
+        Some(Command::CursorsOnLines) => cursors_on_selected_lines(ctx),
         Some(Command::Home) => {
             ctx.selection_range = None;
-            ctx.curr_cursor_pos.0 = 0;
+            let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+            let starts = line_wrap_starts(ctx, line);
+            let start = starts[wrap_segment(&starts, ctx.curr_cursor_pos.0)];
+            // On a wrapped row Home goes to the row start first, then the line start.
+            ctx.curr_cursor_pos.0 = if ctx.curr_cursor_pos.0 > start {
+                start
+            } else {
+                0
+            };
+            update_view_buffer(ctx);
         }
         Some(Command::End) => {
             ctx.selection_range = None;
-            let view_buffer = from_cells_to_string(&ctx.cells);
-            ctx.curr_cursor_pos.0 = view_buffer[ctx.curr_cursor_pos.1]
-                .char_indices()
-                .last()
-                .unwrap()
-                .0;
+            let line = ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1;
+            let len = ctx.buffer.line_len(line);
+            let starts = line_wrap_starts(ctx, line);
+            let seg = wrap_segment(&starts, ctx.curr_cursor_pos.0);
+            let end = segment_col(&starts, seg, len, len);
+            ctx.curr_cursor_pos.0 = if ctx.curr_cursor_pos.0 < end {
+                end
+            } else {
+                len
+            };
+            update_view_buffer(ctx);
         }
         Some(Command::PageUp) => {
             ctx.selection_range = None;
@@ -1086,7 +3269,7 @@ pub async fn update_state(
                 ctx.vert_cell_count
                     .0
                     .saturating_add(ctx.vert_cell_count.1 as usize),
-                ctx.buffer.buf.lines().count(),
+                ctx.buffer.line_count(),
             ) - 1;
 
             ctx.curr_cursor_pos = (0, 0);
@@ -1102,10 +3285,12 @@ pub async fn update_state(
         }
         Some(Command::MoveLeft) => {
             ctx.selection_range = None;
+            move_extra_cursors(ctx, |_, col| col.saturating_sub(1));
             move_cursor_left(ctx);
         }
         Some(Command::MoveRight) => {
             ctx.selection_range = None;
+            move_extra_cursors(ctx, |row, col| (col + 1).min(row.len().saturating_sub(1)));
             move_cursor_right(ctx);
         }
         Some(Command::DeleteWord) => {
@@ -1114,19 +3299,32 @@ pub async fn update_state(
             delete_word(ctx, record);
         }
         Some(Command::Enter) => {
-            if ctx.selection_range.is_some() {
-                delete_selection(ctx, record);
+            if !ctx.extra_cursors.is_empty() {
+                ctx.mode = Modes::Edit;
+                edit_at_cursors(ctx, record, CursorEdit::Enter);
+                return;
             }
-
             ctx.mode = Modes::Edit;
             ctx.is_file_changed = true;
-            let inter_buf_off = get_cursor_pos_to_buf_offset(ctx).unwrap();
-            record.apply(ctx, Change::Enter(inter_buf_off.1));
+            let deleted = take_selection(ctx);
+            let off = edit_offset(ctx, &deleted);
+            // Only the text before the offset decides the indent, the deletion
+            // starts at it.
+            let indent = auto_indent(&ctx.buffer, ctx.tab_width, off);
+            let indent_len = indent.chars().count();
+            apply_over_selection(ctx, record, deleted, Change::Enter(off, indent));
             move_cursor_down(ctx);
-            ctx.curr_cursor_pos.0 = 0;
+            ctx.curr_cursor_pos.0 = indent_len;
             update_view_buffer(ctx);
         }
         Some(Command::Backspace) => {
+            if let Some(block) = ctx.block_selection.take() {
+                block_to_cursors(ctx, block);
+            }
+            if !ctx.extra_cursors.is_empty() {
+                edit_at_cursors(ctx, record, CursorEdit::Backspace);
+                return;
+            }
             if ctx.selection_range.is_some() {
                 ctx.is_file_changed = true;
                 delete_selection(ctx, record);
@@ -1140,46 +3338,53 @@ pub async fn update_state(
                 ctx.is_file_changed = true;
                 record.apply(
                     ctx,
-                    Change::Backspace(
-                        inter_buf_off.1 - 1,
-                        ctx.buffer.buf.chars().nth(inter_buf_off.1 - 1).unwrap(),
-                    ),
+                    Change::Backspace(inter_buf_off.1 - 1, ctx.buffer.char_at(inter_buf_off.1 - 1)),
                 );
             }
             update_view_buffer(ctx);
         }
         Some(Command::Delete) => {
+            if let Some(block) = ctx.block_selection.take() {
+                block_to_cursors(ctx, block);
+            }
+            if !ctx.extra_cursors.is_empty() {
+                edit_at_cursors(ctx, record, CursorEdit::Delete);
+                return;
+            }
             if ctx.selection_range.is_some() {
                 ctx.is_file_changed = true;
                 delete_selection(ctx, record);
                 return;
             }
 
-            if get_cursor_pos_to_buf_offset(ctx).unwrap().1
-                == ctx.buffer.buf.char_indices().last().unwrap().0
-            {
+            if get_cursor_pos_to_buf_offset(ctx).unwrap().1 == ctx.buffer.len_chars() - 1 {
                 ()
             } else {
                 ctx.is_file_changed = true;
                 let inter_buf_off = get_cursor_pos_to_buf_offset(ctx).unwrap();
                 record.apply(
                     ctx,
-                    Change::Delete(
-                        inter_buf_off.1,
-                        ctx.buffer.buf.chars().nth(inter_buf_off.1).unwrap(),
-                    ),
+                    Change::Delete(inter_buf_off.1, ctx.buffer.char_at(inter_buf_off.1)),
                 );
             }
             update_view_buffer(ctx);
         }
         Some(Command::CharPressed(c)) => {
-            if ctx.selection_range.is_some() {
-                delete_selection(ctx, record);
+            if let Some(block) = ctx.block_selection.take() {
+                block_to_cursors(ctx, block);
+            }
+            if !ctx.extra_cursors.is_empty() {
+                edit_at_cursors(ctx, record, CursorEdit::Char(c));
+                return;
+            }
+            if c == '\t' && ctx.selection_range.is_some() {
+                shift_lines(ctx, record, false);
+                return;
             }
-
             ctx.is_file_changed = true;
-            let inter_buf_off = get_cursor_pos_to_buf_offset(ctx).unwrap();
-            record.apply(ctx, Change::InsertChar(inter_buf_off.1, c));
+            let deleted = take_selection(ctx);
+            let off = edit_offset(ctx, &deleted);
+            apply_over_selection(ctx, record, deleted, Change::InsertChar(off, c));
             if c == '\t' {
                 for _ in 0..ctx.tab_width {
                     ctx.curr_cursor_pos.0 += 1;
@@ -1189,6 +3394,148 @@ pub async fn update_state(
             }
             update_view_buffer(ctx);
         }
-        None => (),
+        // A pane at its size limit or a save without changes.
+        Some(Command::GrowPane | Command::ShrinkPane | Command::Save) | None => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_occurrence_wraps_and_skips_taken() {
+        let buffer = Buffer::from_text("ab ab ab\n");
+        assert_eq!(next_occurrence(&buffer, "ab", 1, &[(0, 1)]), Some(3));
+        assert_eq!(next_occurrence(&buffer, "ab", 7, &[(6, 7)]), Some(0));
+        assert_eq!(
+            next_occurrence(&buffer, "ab", 7, &[(0, 1), (3, 4), (6, 7)]),
+            None
+        );
+    }
+
+    #[test]
+    fn next_occurrence_skips_overlapping_selections() {
+        let buffer = Buffer::from_text("aaa\n");
+        assert_eq!(next_occurrence(&buffer, "aa", 2, &[(0, 1)]), None);
+        let buffer = Buffer::from_text("aaaa\n");
+        assert_eq!(next_occurrence(&buffer, "aa", 2, &[(0, 1)]), Some(2));
+    }
+
+    #[test]
+    fn next_occurrence_of_needle_taller_than_the_view() {
+        let block: String = (0..60).map(|i| format!("row {i}\n")).collect();
+        let text = format!("{block}gap\n{block}");
+        let buffer = Buffer::from_text(&text);
+        let len = block.chars().count();
+        let needle = buffer.slice(0..len);
+        assert_eq!(
+            next_occurrence(&buffer, &needle, len, &[(0, len - 1)]),
+            Some(len + 4)
+        );
+    }
+
+    #[test]
+    fn next_occurrence_in_multibyte_text() {
+        let buffer = Buffer::from_text("čaj, čaj\n");
+        assert_eq!(next_occurrence(&buffer, "čaj", 3, &[(0, 2)]), Some(5));
+    }
+
+    #[test]
+    fn next_occurrence_in_empty_buffer() {
+        let buffer = Buffer::from_text("");
+        assert_eq!(next_occurrence(&buffer, "x", 0, &[]), None);
+    }
+
+    #[test]
+    fn line_diff_of_a_changed_middle() {
+        let diff = line_diff("a\nb\nc\nd\n", "a\nx\nc\nd\ne");
+        assert_eq!(diff, ["@@ -2 +2 @@", "+ x", "- b", "  c", "  d", "+ e"]);
+    }
+
+    #[test]
+    fn line_diff_of_equal_and_empty_texts() {
+        assert_eq!(line_diff("", ""), ["@@ -1 +1 @@"]);
+        assert_eq!(line_diff("a\nb", "a\nb\n"), ["@@ -3 +3 @@"]);
+        assert_eq!(line_diff("", "é\n日"), ["@@ -1 +1 @@", "+ é", "+ 日"]);
+        assert_eq!(line_diff("a\n", ""), ["@@ -1 +1 @@", "- a"]);
+    }
+
+    #[test]
+    fn auto_indent_keeps_and_opens_levels() {
+        let buffer = Buffer::from_text("    let a = [\n\tfoo {\nb");
+        assert_eq!(auto_indent(&buffer, 4, 2), "  ");
+        assert_eq!(auto_indent(&buffer, 4, 13), "        ");
+        assert_eq!(auto_indent(&buffer, 4, 12), "    ");
+        assert_eq!(auto_indent(&buffer, 4, 20), "\t\t");
+        // Last line without '\n' and an empty buffer.
+        assert_eq!(auto_indent(&buffer, 4, 22), "");
+        assert_eq!(auto_indent(&Buffer::from_text(""), 4, 0), "");
+    }
+
+    #[test]
+    fn shift_items_indent_non_empty_lines() {
+        let buffer = Buffer::from_text("a\n\n\tb\n  é");
+        assert_eq!(
+            shift_items(&buffer, 2, 0, 3, false),
+            [
+                (0, String::new(), "  ".to_owned()),
+                (3, String::new(), "\t".to_owned()),
+                (6, String::new(), "  ".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn shift_items_outdent_one_level() {
+        let buffer = Buffer::from_text("     a\n\t\tb\n c\nd\n");
+        assert_eq!(
+            shift_items(&buffer, 4, 0, 3, true),
+            [
+                (0, "    ".to_owned(), String::new()),
+                (7, "\t".to_owned(), String::new()),
+                (11, " ".to_owned(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_rows_clamp_to_each_line() {
+        let buffer = Buffer::from_text("abcdef\nab\n\nžluťoučký");
+        // Corners given bottom right first, the right column is exclusive.
+        let block = ((3, 4), (0, 1));
+        assert_eq!(block_bounds(block), ((0, 3), (1, 4)));
+        assert_eq!(
+            block_rows(&buffer, block),
+            [(1, 4), (8, 9), (10, 10), (12, 15)]
+        );
+        assert_eq!(block_text(&buffer, block), "bcd\nb\n\nluť");
+    }
+
+    #[test]
+    fn block_past_the_end_of_lines_and_empty_buffer() {
+        let buffer = Buffer::from_text("ab\ncd\n");
+        assert_eq!(block_rows(&buffer, ((0, 5), (1, 9))), [(2, 2), (5, 5)]);
+        assert_eq!(block_text(&buffer, ((0, 5), (1, 9))), "\n");
+        let empty = Buffer::from_text("");
+        assert_eq!(block_rows(&empty, ((0, 0), (0, 2))), [(0, 0)]);
+    }
+
+    #[test]
+    fn replacement_expands_capture_groups() {
+        let query = Query {
+            pattern: r"(?P<key>\w+)=(\d+)".to_owned(),
+            is_case_sensitive: true,
+            is_regex: true,
+            is_whole_word: false,
+            scope: None,
+        };
+        let re = build_regex(&query).unwrap();
+        let text = "ä=1 b=2";
+        let template = "$2:${key}";
+        assert_eq!(expand_replacement(template, Some(&re), text, 0), "1:ä");
+        assert_eq!(expand_replacement(template, Some(&re), text, 5), "2:b");
+        // Plain text mode takes the replacement as it is.
+        assert_eq!(expand_replacement(template, None, text, 0), template);
     }
 }
@@ -0,0 +1,236 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+};
+
+use ignore::WalkBuilder;
+
+// Quick open stops indexing after this many files.
+pub const MAX_INDEXED_FILES: usize = 50_000;
+
+const BATCH_SIZE: usize = 256;
+
+// Bonus of the most recently opened file, older ones get a little less each.
+const RECENT_BONUS: i32 = 50;
+
+pub enum IndexMsg {
+    // Paths relative to the indexed root.
+    Files(Vec<PathBuf>),
+    Done,
+}
+
+// Nearest directory above the document holding a .git directory, or the
+// document's own directory when it is not part of a repository.
+pub fn project_root(doc: &Path) -> PathBuf {
+    let dir = match doc.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    dir.ancestors()
+        .find(|d| d.join(".git").exists())
+        .unwrap_or(&dir)
+        .to_path_buf()
+}
+
+// Walks the tree under root on its own thread with the same .gitignore and hidden
+// file rules as find in files. Dropping the job stops the walk.
+pub struct FileIndexJob {
+    rx: Receiver<IndexMsg>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl FileIndexJob {
+    pub fn spawn(root: PathBuf) -> FileIndexJob {
+        let (tx, rx) = mpsc::channel();
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&is_stopped);
+        thread::spawn(move || {
+            let mut batch = Vec::new();
+            let mut count = 0;
+            for entry in WalkBuilder::new(&root).require_git(false).build() {
+                if stop.load(Ordering::Relaxed) || count >= MAX_INDEXED_FILES {
+                    break;
+                }
+                let Ok(entry) = entry else {
+                    continue;
+                };
+                if !entry.file_type().is_some_and(|t| t.is_file()) {
+                    continue;
+                }
+                let path = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+                batch.push(path.to_path_buf());
+                count += 1;
+                if batch.len() >= BATCH_SIZE
+                    && tx
+                        .send(IndexMsg::Files(std::mem::take(&mut batch)))
+                        .is_err()
+                {
+                    return;
+                }
+            }
+            if !batch.is_empty() {
+                let _ = tx.send(IndexMsg::Files(batch));
+            }
+            let _ = tx.send(IndexMsg::Done);
+        });
+        FileIndexJob { rx, is_stopped }
+    }
+
+    pub fn poll(&self) -> Vec<IndexMsg> {
+        self.rx.try_iter().collect()
+    }
+}
+
+impl Drop for FileIndexJob {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+fn is_separator(c: char) -> bool {
+    matches!(c, '/' | '\\' | '_' | '-' | '.' | ' ')
+}
+
+// Score of the pattern chars appearing in order inside the candidate, ignoring
+// case, or None when they do not. Runs of consecutive chars, matches at the start
+// of a word and inside the file name score higher, skipped chars cost a little.
+pub fn fuzzy_score(pattern: &str, candidate: &str) -> Option<i32> {
+    let chars: Vec<char> = candidate.chars().collect();
+    let name_start = chars
+        .iter()
+        .rposition(|&c| c == '/' || c == '\\')
+        .map_or(0, |i| i + 1);
+    let mut score = 0;
+    let mut idx = 0;
+    let mut last_match: Option<usize> = None;
+    for p in pattern.chars().filter(|c| !c.is_whitespace()) {
+        let found = (idx..chars.len()).find(|&i| chars[i].to_lowercase().eq(p.to_lowercase()))?;
+        let c = chars[found];
+        let prev = found.checked_sub(1).map(|i| chars[i]);
+        if let Some(last) = last_match {
+            if found == last + 1 {
+                score += 5;
+            } else {
+                score -= std::cmp::min(found - last - 1, 10) as i32;
+            }
+        }
+        if prev.is_none_or(is_separator)
+            || (prev.is_some_and(char::is_lowercase) && c.is_uppercase())
+        {
+            score += 8;
+        }
+        if found >= name_start {
+            score += 3;
+        }
+        if c == p {
+            score += 1;
+        }
+        last_match = Some(found);
+        idx = found + 1;
+    }
+    // Shorter paths win between otherwise equal matches.
+    Some(score * 4 - chars.len() as i32 / 8)
+}
+
+// (index, score) of the files matching the pattern, best first, indices counting
+// from first. Recent files are given most recent first and also lead the list
+// while the pattern is empty.
+pub fn rank_files(
+    files: &[PathBuf],
+    first: usize,
+    pattern: &str,
+    recent: &[PathBuf],
+) -> Vec<(usize, i32)> {
+    let mut ranked: Vec<(usize, i32)> = files
+        .iter()
+        .enumerate()
+        .filter_map(|(i, f)| {
+            let score = fuzzy_score(pattern, &f.to_string_lossy())?;
+            let bonus = recent
+                .iter()
+                .position(|r| r == f)
+                .map_or(0, |pos| RECENT_BONUS - pos as i32);
+            Some((first + i, score + bonus))
+        })
+        .collect();
+    ranked.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+    ranked
+}
+
+// Merges the ranking of files indexed later into the existing one. Ties keep the
+// earlier file first, the same order ranking them all at once gives.
+pub fn merge_ranked(old: Vec<(usize, i32)>, new: Vec<(usize, i32)>) -> Vec<(usize, i32)> {
+    let mut res = Vec::with_capacity(old.len() + new.len());
+    let mut new = new.into_iter().peekable();
+    for item in old {
+        while let Some(n) = new.next_if(|n| n.1 > item.1) {
+            res.push(n);
+        }
+        res.push(item);
+    }
+    res.extend(new);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn fuzzy_score_needs_chars_in_order() {
+        assert!(fuzzy_score("edr", "src/editor.rs").is_some());
+        assert!(fuzzy_score("EDR", "src/editor.rs").is_some());
+        assert!(fuzzy_score("rde", "src/editor.rs").is_none());
+        assert!(fuzzy_score("x", "").is_none());
+        assert_eq!(fuzzy_score("", ""), Some(0));
+        assert!(fuzzy_score("žl", "src/žluť.rs").is_some());
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_file_names_and_word_starts() {
+        let name = fuzzy_score("edi", "src/editor.rs").unwrap();
+        let dir = fuzzy_score("edi", "editor/src/mod.rs").unwrap();
+        assert!(name > dir);
+        let start = fuzzy_score("fb", "foo_bar.rs").unwrap();
+        let middle = fuzzy_score("fb", "fab.rs").unwrap();
+        assert!(start > middle);
+    }
+
+    #[test]
+    fn rank_files_puts_recent_first_while_empty() {
+        let files = paths(&["a.rs", "b.rs", "c.rs"]);
+        let ranked = rank_files(&files, 0, "", &paths(&["c.rs", "b.rs"]));
+        let order: Vec<usize> = ranked.iter().map(|&(i, _)| i).collect();
+        assert_eq!(order, [2, 1, 0]);
+        assert!(rank_files(&[], 0, "a", &[]).is_empty());
+    }
+
+    #[test]
+    fn merging_batches_matches_ranking_all_at_once() {
+        let files = paths(&[
+            "src/main.rs",
+            "src/editor.rs",
+            "docs/readme.md",
+            "src/render.rs",
+            "README.md",
+            "tests/re.rs",
+        ]);
+        let all = rank_files(&files, 0, "re", &[]);
+        let merged = merge_ranked(
+            rank_files(&files[..3], 0, "re", &[]),
+            rank_files(&files[3..], 3, "re", &[]),
+        );
+        assert_eq!(merged, all);
+        assert_eq!(merge_ranked(Vec::new(), all.clone()), all);
+    }
+}
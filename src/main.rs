@@ -1,4 +1,4 @@
-use editor::update_state;
+use editor::{open_buffer, update_state};
 use macroquad::miniquad::conf::Icon;
 use macroquad::window::Conf;
 use rfd::FileDialog;
@@ -9,7 +9,13 @@ mod buffer;
 mod config;
 mod core;
 mod editor;
+mod finder;
+mod pane;
 mod render;
+mod rope;
+mod search;
+mod syntax;
+mod theme;
 use crate::core::*;
 use crate::render::render;
 
@@ -58,6 +64,9 @@ async fn main() {
         };
 
         init(&mut ctx, &config, &path).await;
+        for extra in std::env::args().skip(2) {
+            open_buffer(&mut ctx, &mut record, &PathBuf::from(extra)).await;
+        }
 
         while !ctx.is_exit {
             update_state(&mut ctx, &mut record, &bell).await;
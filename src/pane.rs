@@ -0,0 +1,194 @@
+use macroquad::math::Rect;
+
+// View state of a pane that is not focused. The focused pane lives directly in
+// Context (cursor and scroll), its slot in Context::panes is only refreshed when
+// focus leaves it.
+#[derive(Default, Clone)]
+pub struct Pane {
+    pub buf_idx: usize,
+    pub curr_cursor_pos: (usize, usize),
+    pub vert_first_line: usize,
+    pub horiz_offset: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitDir {
+    // Panes stacked on top of each other.
+    Horizontal,
+    // Panes side by side.
+    Vertical,
+}
+
+#[derive(Debug)]
+pub enum Layout {
+    Pane(usize),
+    // Direction, share of the first child and the two children.
+    Split(SplitDir, f32, Box<Layout>, Box<Layout>),
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Pane(0)
+    }
+}
+
+impl Layout {
+    pub fn rects(&self, area: Rect, out: &mut Vec<(usize, Rect)>) {
+        match self {
+            Layout::Pane(id) => out.push((*id, area)),
+            Layout::Split(dir, ratio, first, second) => {
+                let (a, b) = match dir {
+                    SplitDir::Horizontal => {
+                        let h = (area.h * ratio).floor();
+                        (
+                            Rect::new(area.x, area.y, area.w, h),
+                            Rect::new(area.x, area.y + h, area.w, area.h - h),
+                        )
+                    }
+                    SplitDir::Vertical => {
+                        let w = (area.w * ratio).floor();
+                        (
+                            Rect::new(area.x, area.y, w, area.h),
+                            Rect::new(area.x + w, area.y, area.w - w, area.h),
+                        )
+                    }
+                };
+                first.rects(a, out);
+                second.rects(b, out);
+            }
+        }
+    }
+
+    pub fn split_pane(&mut self, pane: usize, new_pane: usize, dir: SplitDir) -> bool {
+        match self {
+            Layout::Pane(id) if *id == pane => {
+                *self = Layout::Split(
+                    dir,
+                    0.5,
+                    Box::new(Layout::Pane(pane)),
+                    Box::new(Layout::Pane(new_pane)),
+                );
+                true
+            }
+            Layout::Pane(_) => false,
+            Layout::Split(_, _, first, second) => {
+                first.split_pane(pane, new_pane, dir) || second.split_pane(pane, new_pane, dir)
+            }
+        }
+    }
+
+    // Replaces the split holding the pane with the pane's sibling and renumbers
+    // the remaining panes so they keep matching indices in Context::panes.
+    pub fn remove_pane(&mut self, pane: usize) {
+        self.remove_leaf(pane);
+        self.renumber(pane);
+    }
+
+    fn remove_leaf(&mut self, pane: usize) -> bool {
+        if let Layout::Split(_, _, first, second) = self {
+            if matches!(**first, Layout::Pane(id) if id == pane) {
+                *self = std::mem::take(&mut **second);
+                return true;
+            }
+            if matches!(**second, Layout::Pane(id) if id == pane) {
+                *self = std::mem::take(&mut **first);
+                return true;
+            }
+            return first.remove_leaf(pane) || second.remove_leaf(pane);
+        }
+        false
+    }
+
+    fn renumber(&mut self, removed: usize) {
+        match self {
+            Layout::Pane(id) => {
+                if *id > removed {
+                    *id -= 1;
+                }
+            }
+            Layout::Split(_, _, first, second) => {
+                first.renumber(removed);
+                second.renumber(removed);
+            }
+        }
+    }
+
+    // Grows (positive delta) or shrinks the pane inside its closest split.
+    pub fn resize_pane(&mut self, pane: usize, delta: f32) -> bool {
+        if let Layout::Split(_, ratio, first, second) = self {
+            if matches!(**first, Layout::Pane(id) if id == pane) {
+                *ratio = (*ratio + delta).clamp(0.1, 0.9);
+                return true;
+            }
+            if matches!(**second, Layout::Pane(id) if id == pane) {
+                *ratio = (*ratio - delta).clamp(0.1, 0.9);
+                return true;
+            }
+            return first.resize_pane(pane, delta) || second.resize_pane(pane, delta);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rects(layout: &Layout) -> Vec<(usize, Rect)> {
+        let mut out = Vec::new();
+        layout.rects(Rect::new(0f32, 0f32, 100f32, 50f32), &mut out);
+        out
+    }
+
+    fn ids(layout: &Layout) -> Vec<usize> {
+        rects(layout).into_iter().map(|(id, _)| id).collect()
+    }
+
+    #[test]
+    fn split_pane_shares_the_area() {
+        let mut layout = Layout::default();
+        assert!(layout.split_pane(0, 1, SplitDir::Vertical));
+        assert!(layout.split_pane(1, 2, SplitDir::Horizontal));
+        assert!(!layout.split_pane(7, 3, SplitDir::Vertical));
+        assert_eq!(
+            rects(&layout),
+            [
+                (0, Rect::new(0f32, 0f32, 50f32, 50f32)),
+                (1, Rect::new(50f32, 0f32, 50f32, 25f32)),
+                (2, Rect::new(50f32, 25f32, 50f32, 25f32)),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_pane_renumbers_the_rest() {
+        let mut layout = Layout::default();
+        layout.split_pane(0, 1, SplitDir::Vertical);
+        layout.split_pane(0, 2, SplitDir::Horizontal);
+        assert_eq!(ids(&layout), [0, 2, 1]);
+        layout.remove_pane(0);
+        assert_eq!(ids(&layout), [1, 0]);
+        assert_eq!(rects(&layout)[0].1, Rect::new(0f32, 0f32, 50f32, 50f32));
+        layout.remove_pane(1);
+        assert_eq!(rects(&layout), [(0, Rect::new(0f32, 0f32, 100f32, 50f32))]);
+    }
+
+    #[test]
+    fn removing_the_last_pane_keeps_it() {
+        let mut layout = Layout::default();
+        layout.remove_pane(0);
+        assert_eq!(ids(&layout), [0]);
+    }
+
+    #[test]
+    fn resize_pane_is_clamped() {
+        let mut layout = Layout::default();
+        assert!(!layout.resize_pane(0, 0.1));
+        layout.split_pane(0, 1, SplitDir::Vertical);
+        assert!(layout.resize_pane(1, 0.2));
+        assert_eq!(rects(&layout)[1].1.w, 70f32);
+        assert!(layout.resize_pane(0, 1f32));
+        assert_eq!(rects(&layout)[0].1.w, 90f32);
+        assert!(!layout.resize_pane(2, 0.1));
+    }
+}
@@ -1,10 +1,16 @@
-use macroquad::{prelude::*, window};
+use macroquad::prelude::*;
+use std::path::Path;
 
 use crate::{
+    buffer::Buffer,
     core::{Context, Modes},
-    editor::get_ch_off_to_inline_off,
+    pane::Pane,
+    search::MAX_FILE_MATCHES,
+    syntax::TokenKind,
 };
 
+const SCROLLBAR_WIDTH: f32 = 8f32;
+
 pub const HELP_PAGE: &str = "
 
         HELP PAGE
@@ -17,22 +23,76 @@ CTRL - S ==> Save document.
 
 CTRL - O ==> Open Document.
 
+CTRL - Tab / CTRL - Shift - Tab ==> Switch to next/previous buffer.
+
+CTRL - B ==> List open buffers.
+
+CTRL - P ==> Quick open a project file by typing parts of its path.
+
+CTRL - Shift - W ==> Close current buffer.
+
+CTRL - T ==> Switch color theme, Enter keeps it, ESC goes back.
+
+Alt - V / Alt - S ==> Split pane side by side / stacked.
+
+Alt - Q ==> Close current pane.
+
+Alt - Left/Right/Up/Down ==> Move focus to neighbouring pane.
+
+Alt - =/- ==> Grow/shrink current pane.
+
+Mouse click on line numbers ==> Select the whole line.
+Mouse double/triple-click ==> Select word/line, drag extends by words/lines.
+Shift + Mouse click ==> Extend selection to the clicked position.
+CTRL + Mouse click ==> Add a cursor, or remove the extra cursor clicked on.
+
+CTRL - D ==> Select next occurrence of the selection with another cursor.
+
+CTRL - Shift - L ==> Put a cursor at the end of every selected line.
+
+Alt - Shift - Arrows, Alt - Mouse drag ==> Select a rectangular block. Copy, cut and paste work on it as a column, typing inserts on every row.
+
+Mouse on tab bar ==> Click to switch, drag to reorder, middle-click to close.
+
 PageUp/Down ==> PageUp/PageDown.
 
 CTRL - PageUp/Down ==> Goto top/bottom of document.
 
+Mouse wheel ==> Scroll up/down.
+
+Shift - PageUp/Down, Shift - Mouse wheel ==> Scroll left/right.
+
 CTRL - G ==> Go To Line mode.
 
 CTRL - H ==> Open help page.
 
+CTRL - L ==> Convert line endings between LF and CRLF.
+
 CTRL - F ==> Search document in case sensitive mode.
 
 CTRL - Shift - F ==> Search document in case insensitive mode.
 
+Enter / Shift - Enter (in find prompt) ==> Jump to next/previous match.
+
+CTRL - Enter (in find prompt) ==> Stay at the current match, ESC goes back.
+
+Alt - R (in find prompt) ==> Toggle regular expression search.
+
+Alt - W / Alt - L (in find prompt) ==> Toggle whole word / in selection search.
+
+Alt - F ==> Find in files under the directory of the current document.
+    Enter searches, then opens the selected match, Alt - C toggles case sensitivity.
+
+CTRL - R / CTRL - Shift - R ==> Replace in case sensitive/insensitive mode.
+    Tab switches between the find and replace fields, Enter replaces the
+    current match, Up/Down skip between matches, Alt - A replaces all.
+
 Home ==> Go to begining of line.
 
 End ==> Go to end of line.
 
+Alt - Z ==> Toggle soft wrap, Home/End then go to the start/end of the wrapped row first.
+
 Left/Right Arrow ==> Move cursor by one char left/right.
 
 Shift Up/Down/Left/Right ==> Select text.
@@ -45,6 +105,10 @@ Delete ==> Delete char under cursor.
 
 Backspace ==> Remove previous char.
 
+Enter ==> New line keeping the indent, one level deeper after an opening bracket or a trailing ':'.
+
+Tab / Shift - Tab (with a selection) ==> Indent/outdent the selected lines.
+
 CTRL - C/X/V ==> Copy/Cut/Paste operation.
 
 CTRL - W ==> Delete previous word surround by space.
@@ -57,103 +121,439 @@ pub struct Cell {
     pub coord: (f32, f32),
     pub bound: (f32, f32),
     pub pos: (usize, usize),
+    // Visual row inside the pane, differs from pos.1 once a line above is wrapped.
+    pub row: usize,
+    pub fg_color: Color,
     pub bg_color: std::cell::Cell<Color>,
 }
 
+fn token_color(ctx: &Context, kind: Option<&TokenKind>) -> Color {
+    match kind {
+        Some(&kind) if kind != TokenKind::Normal && ctx.is_syntax_highlight => {
+            ctx.theme.tokens[kind as usize]
+        }
+        _ => ctx.theme.font,
+    }
+}
+
 pub fn from_str_to_cells(ctx: &mut Context) {
-    let mut cells: Vec<Cell> = Default::default();
-    let mut x_coor;
-    let mut y_coor = 0f32;
-    let end_rng = std::cmp::min(
-        ctx.vert_cell_count.0 + ctx.vert_cell_count.1,
-        ctx.buffer.buf.lines().count(),
+    // A row partly scrolled out at the top leaves room for one more at the bottom.
+    let rows = ctx.vert_cell_count.1 + usize::from(ctx.scroll_px > 0f32);
+    let cells = layout_cells(
+        ctx,
+        &ctx.buffer,
+        ctx.vert_cell_count.0,
+        rows,
+        ctx.viewport,
+        (ctx.horiz_offset, ctx.scroll_px),
     );
+    ctx.cells = cells;
+}
 
-    let mut prev_lf_idx;
-    let mut y_line_off = 0usize;
+// Room for text in a pane, right of the gutter and left of the scrollbar.
+pub fn wrap_width(ctx: &Context, buffer: &Buffer, rect: Rect) -> f32 {
+    rect.w - gutter_width(ctx, buffer) - SCROLLBAR_WIDTH
+}
 
-    for (line_idx, (lf_idx, _)) in ctx
-        .buffer
-        .buf
-        .match_indices('\n')
-        .enumerate()
-        .filter(|l| (ctx.vert_cell_count.0..end_rng).contains(&l.0))
-    {
-        let lf_off = get_ch_off_to_inline_off(ctx, lf_idx);
-        prev_lf_idx = (lf_idx - lf_off).saturating_sub(1);
-        let line;
-        if line_idx == 0 {
-            line = &ctx.buffer.buf[0..=lf_idx];
-        } else {
-            line = &ctx.buffer.buf[prev_lf_idx + 1..=lf_idx];
+// Part of a pane right of the gutter, text scrolled to the left is clipped here.
+fn text_rect(ctx: &Context, buffer: &Buffer, rect: Rect) -> Rect {
+    let gutter = gutter_width(ctx, buffer);
+    Rect::new(rect.x + gutter, rect.y, rect.w - gutter, rect.h)
+}
+
+// (x, width) of every char of a line, x counted from the start of the line.
+fn char_advances(ctx: &Context, line: &str) -> Vec<(f32, f32)> {
+    if let Some(w) = ctx.is_font_monospaced {
+        let mut x = 0f32;
+        line.chars()
+            .map(|ch| {
+                // Wide glyphs (CJK, emoji) do not fit the fixed monospace advance.
+                let char_w = if ch.is_ascii() {
+                    w
+                } else {
+                    measure_text(&ch.to_string(), Some(ctx.font), ctx.font_size, 1f32)
+                        .width
+                        .max(w)
+                };
+                x += char_w;
+                (x - char_w, char_w)
+            })
+            .collect()
+    } else {
+        line.char_indices()
+            .map(|(byte_idx, ch)| {
+                (
+                    measure_text(&line[..byte_idx], Some(ctx.font), ctx.font_size, 1f32).width,
+                    measure_text(&ch.to_string(), Some(ctx.font), ctx.font_size, 1f32).width,
+                )
+            })
+            .collect()
+    }
+}
+
+// Columns where the visual rows of a wrapped line start, the first one is always 0.
+// A row ends after the last whitespace that still fits, words longer than the
+// whole row are broken where they hit the edge.
+fn wrap_starts(chars: &[char], advances: &[(f32, f32)], width: f32) -> Vec<usize> {
+    let mut starts = vec![0];
+    let mut row_x = 0f32;
+    let mut word_break = None;
+    for (j, &ch) in chars.iter().enumerate() {
+        if ch == '\n' || ch == '\r' {
+            continue;
         }
-        if let Some(w) = ctx.is_font_monospaced {
-            for (j, ch) in line.chars().enumerate() {
-                x_coor = j as f32 * w;
-                cells.push(Cell {
-                    c: ch,
-                    coord: (x_coor, y_coor),
-                    bound: (w, ctx.font_size as f32),
-                    pos: (j, y_line_off),
-                    bg_color: std::cell::Cell::new(ctx.bg_color),
-                });
-            }
+        let (x, w) = advances[j];
+        while x + w - row_x > width && j > *starts.last().unwrap() {
+            let at = match word_break.take() {
+                Some(b) if b > *starts.last().unwrap() => b,
+                _ => j,
+            };
+            starts.push(at);
+            row_x = advances[at].0;
+        }
+        if ch.is_whitespace() {
+            word_break = Some(j + 1);
+        }
+    }
+    starts
+}
+
+// Row starts of a line of the active buffer as laid out in the active pane, just
+// [0] while soft wrap is off.
+pub fn line_wrap_starts(ctx: &Context, line_idx: usize) -> Vec<usize> {
+    if !ctx.is_soft_wrap {
+        return vec![0];
+    }
+    let line = ctx.buffer.line(line_idx);
+    let chars: Vec<char> = line.chars().collect();
+    let width = wrap_width(ctx, &ctx.buffer, ctx.viewport);
+    wrap_starts(&chars, &char_advances(ctx, &line), width)
+}
+
+// (x, width) of a column of a line of the active buffer, x counted from the start
+// of the line without any scrolling.
+pub fn column_span(ctx: &Context, line_idx: usize, col: usize) -> (f32, f32) {
+    let line = ctx.buffer.line(line_idx);
+    char_advances(ctx, &line)
+        .get(col)
+        .copied()
+        .unwrap_or((0f32, 0f32))
+}
+
+// Lays out the lines from first_line on until rows visual rows are filled, moved
+// by the scrolled (x, y) pixels. Cell positions stay logical (column, line -
+// first_line), wrapped rows only change where the cells are drawn.
+pub fn layout_cells(
+    ctx: &Context,
+    buffer: &Buffer,
+    first_line: usize,
+    rows: usize,
+    rect: Rect,
+    scroll: (f32, f32),
+) -> Vec<Cell> {
+    let mut cells: Vec<Cell> = Default::default();
+    let origin = (rect.x + gutter_width(ctx, buffer), rect.y);
+    let width = wrap_width(ctx, buffer, rect);
+    let mut row = 0;
+
+    for (y_line_off, line_idx) in (first_line..buffer.line_count()).enumerate() {
+        if row >= rows {
+            break;
+        }
+        let line = buffer.line(line_idx);
+        let tokens = if ctx.is_syntax_highlight {
+            buffer.line_tokens(line_idx)
+        } else {
+            None
+        };
+        let chars: Vec<char> = line.chars().collect();
+        let advances = char_advances(ctx, &line);
+        let starts = if ctx.is_soft_wrap {
+            wrap_starts(&chars, &advances, width)
         } else {
-            for (j, ch) in line.chars().enumerate() {
-                let letter_size =
-                    measure_text(&ch.to_string(), Some(ctx.font), ctx.font_size, 1.0f32);
-                x_coor = measure_text(&line[..j], Some(ctx.font), ctx.font_size, 1f32).width;
-                cells.push(Cell {
-                    c: ch,
-                    coord: (x_coor, y_coor),
-                    bound: (letter_size.width, ctx.font_size as f32),
-                    pos: (j, y_line_off),
-                    bg_color: std::cell::Cell::new(ctx.bg_color),
-                });
+            vec![0]
+        };
+        let mut seg = 0;
+        let mut row_x = 0f32;
+        for (j, &ch) in chars.iter().enumerate() {
+            if starts.get(seg + 1) == Some(&j) {
+                seg += 1;
+                row += 1;
+                row_x = advances[j].0;
             }
+            if row >= rows {
+                break;
+            }
+            cells.push(Cell {
+                c: ch,
+                coord: (
+                    origin.0 + advances[j].0 - row_x - scroll.0,
+                    origin.1 + row as f32 * ctx.font_size as f32 - scroll.1,
+                ),
+                bound: (advances[j].1, ctx.font_size as f32),
+                pos: (j, y_line_off),
+                row,
+                fg_color: token_color(ctx, tokens.as_ref().and_then(|t| t.get(j))),
+                bg_color: std::cell::Cell::new(ctx.theme.bg),
+            });
         }
-        y_coor = (y_line_off + 1) as f32 * ctx.font_size as f32;
-        y_line_off += 1;
+        row += 1;
     }
-    ctx.cells = cells;
+    cells
+}
+
+// Width of one column, for margins and scroll steps given in columns.
+pub fn column_width(ctx: &Context) -> f32 {
+    ctx.is_font_monospaced
+        .unwrap_or_else(|| measure_text("m", Some(ctx.font), ctx.font_size, 1f32).width)
+}
+
+fn gutter_digit_width(ctx: &Context) -> f32 {
+    measure_text("0", Some(ctx.font), ctx.font_size, 1f32).width
+}
+
+// Wide enough for the buffer's last line number and one digit of padding, 0 when
+// line numbers are turned off.
+pub fn gutter_width(ctx: &Context, buffer: &Buffer) -> f32 {
+    if !ctx.is_line_numbers {
+        return 0f32;
+    }
+    (gutter_digits(buffer.line_count()) + 1) as f32 * gutter_digit_width(ctx)
+}
+
+// Room for the largest line number, and at least two digits.
+fn gutter_digits(line_count: usize) -> usize {
+    std::cmp::max(line_count.to_string().len(), 2)
 }
 
-pub fn from_cells_to_string(cells: &Vec<Cell>) -> Vec<String> {
-    let mut s = String::new();
-    let mut vec_of_string = Vec::<String>::new();
-    cells.iter().for_each(|c| s.push(c.c));
-    for l in s.lines() {
-        vec_of_string.push(l.to_owned());
-        vec_of_string.iter_mut().last().unwrap().push('\n');
+// Number shown beside the line, its distance to the cursor line in relative mode.
+fn line_number(line: usize, cursor_line: usize, is_relative: bool) -> usize {
+    if is_relative && line != cursor_line {
+        line.abs_diff(cursor_line)
+    } else {
+        line + 1
     }
-    vec_of_string
 }
 
-fn draw_cursor_location(ctx: &Context) {
+// Line numbers down the left edge of a pane, counted from the cursor line when
+// relative numbering is on. The cursor line always shows its absolute number, the
+// continuation rows of a wrapped line are left blank.
+fn draw_gutter(
+    ctx: &Context,
+    buffer: &Buffer,
+    cells: &[Cell],
+    first_line: usize,
+    rect: Rect,
+    cursor_line: usize,
+) {
+    let width = gutter_width(ctx, buffer);
+    if width == 0f32 {
+        return;
+    }
+    draw_rectangle(rect.x, rect.y, width, rect.h, ctx.theme.gutter);
+    for cell in cells.iter().filter(|c| c.pos.0 == 0) {
+        let line = first_line + cell.pos.1;
+        let label = line_number(line, cursor_line, ctx.is_relative_line_numbers).to_string();
+        let label_w = measure_text(&label, Some(ctx.font), ctx.font_size, 1f32).width;
+        draw_text_ex(
+            &label,
+            rect.x + width - label_w - gutter_digit_width(ctx) / 2f32,
+            cell.coord.1 + 12f32,
+            TextParams {
+                font_size: ctx.font_size,
+                color: if line == cursor_line {
+                    ctx.theme.gutter_current
+                } else {
+                    ctx.theme.gutter_font
+                },
+                font: ctx.font,
+                ..Default::default()
+            },
+        );
+    }
+}
+
+pub fn tab_bar_height(ctx: &Context) -> f32 {
+    ctx.font_size as f32
+}
+
+// Prompts and status messages are drawn over the status bar.
+pub fn status_bar_height(ctx: &Context) -> f32 {
+    ctx.font_size as f32
+}
+
+// Window area between the tab bar and the status bar that is shared between the panes.
+pub fn text_area(ctx: &Context) -> Rect {
+    let top = tab_bar_height(ctx);
+    Rect::new(
+        0f32,
+        top,
+        screen_width(),
+        screen_height() - top - status_bar_height(ctx),
+    )
+}
+
+pub fn pane_rects(ctx: &Context) -> Vec<(usize, Rect)> {
+    let mut rects = Vec::new();
+    ctx.layout.rects(text_area(ctx), &mut rects);
+    rects
+}
+
+// Clips everything drawn afterwards to the rect, None turns clipping off.
+fn set_clip(rect: Option<Rect>) {
+    // SAFETY: only called from render(), on the thread owning the GL context.
+    let gl = unsafe { get_internal_gl() };
+    gl.quad_gl
+        .scissor(rect.map(|r| (r.x as i32, r.y as i32, r.w as i32, r.h as i32)));
+}
+
+fn draw_inactive_pane(ctx: &Context, pane: &Pane, rect: Rect) {
+    let buffer = if pane.buf_idx == ctx.active_idx {
+        &ctx.buffer
+    } else {
+        &ctx.buffers[pane.buf_idx].buffer
+    };
+    let rows = rect.h as usize / ctx.font_size as usize + 1;
+    let first_line = std::cmp::min(pane.vert_first_line, buffer.line_count().saturating_sub(1));
+    let cells = layout_cells(
+        ctx,
+        buffer,
+        first_line,
+        rows,
+        rect,
+        (pane.horiz_offset, 0f32),
+    );
+    set_clip(Some(rect));
+    draw_gutter(
+        ctx,
+        buffer,
+        &cells,
+        first_line,
+        rect,
+        first_line + pane.curr_cursor_pos.1,
+    );
+    set_clip(Some(text_rect(ctx, buffer, rect)));
+    for cell in cells.iter() {
+        if cell.c == '\n' || cell.c == '\r' {
+            continue;
+        }
+        draw_text_ex(
+            &cell.c.to_string(),
+            cell.coord.0,
+            cell.coord.1 + 12f32,
+            TextParams {
+                font_size: ctx.font_size,
+                color: cell.fg_color,
+                font: ctx.font,
+                ..Default::default()
+            },
+        );
+    }
+    if let Some(c) = cells.iter().find(|c| c.pos == pane.curr_cursor_pos) {
+        draw_rectangle_lines(
+            c.coord.0,
+            c.coord.1,
+            c.bound.0,
+            c.bound.1,
+            1f32,
+            ctx.theme.cursor,
+        );
+    }
+    set_clip(None);
+}
+
+fn draw_pane_borders(ctx: &Context) {
+    for (id, rect) in pane_rects(ctx) {
+        let color = if id == ctx.active_pane {
+            ctx.theme.pane_border_active
+        } else {
+            ctx.theme.pane_border
+        };
+        draw_rectangle_lines(rect.x, rect.y, rect.w, rect.h, 1f32, color);
+    }
+}
+
+pub fn buffer_display_name(path: &Path, is_changed: bool) -> String {
+    format!(
+        "{}{}",
+        if is_changed { "[+] " } else { "" },
+        path.file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    )
+}
+
+// (x, width, label) of every tab, in the same order as ctx.buffers.
+pub fn tab_layout(ctx: &Context) -> Vec<(f32, f32, String)> {
+    let mut x = 0f32;
+    ctx.buffers
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            let (path, is_changed) = if i == ctx.active_idx {
+                (&ctx.active_buf, ctx.is_file_changed)
+            } else {
+                (&b.active_buf, b.is_file_changed)
+            };
+            let label = format!(" {} ", buffer_display_name(path, is_changed));
+            let w = measure_text(&label, Some(ctx.font), ctx.font_size, 1f32).width;
+            let tab = (x, w, label);
+            x += w + 2f32;
+            tab
+        })
+        .collect()
+}
+
+fn draw_tab_bar(ctx: &Context) {
+    let h = tab_bar_height(ctx);
+    draw_rectangle(0f32, 0f32, screen_width(), h, ctx.theme.tab_bar);
+    for (i, (x, w, label)) in tab_layout(ctx).iter().enumerate() {
+        let (bg, fg) = if i == ctx.active_idx {
+            (ctx.theme.tab_active_bg, ctx.theme.tab_active_fg)
+        } else {
+            (ctx.theme.tab_bg, ctx.theme.tab_fg)
+        };
+        draw_rectangle(*x, 0f32, *w, h, bg);
+        draw_text_ex(
+            label,
+            *x,
+            12f32,
+            TextParams {
+                font_size: ctx.font_size,
+                color: fg,
+                font: ctx.font,
+                ..Default::default()
+            },
+        );
+    }
+}
+
+// Cursor location and document details, right aligned in the status bar.
+fn draw_status_bar(ctx: &Context) {
     let loc_str = format!(
-        "{} {}:{}:{}",
+        "{} {}:{}:{} {}{}{}",
         if ctx.is_file_changed { "[+] " } else { "" },
         ctx.active_buf.file_name().unwrap().to_str().unwrap(),
         ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1 + 1,
-        ctx.curr_cursor_pos.0 + 1
+        ctx.curr_cursor_pos.0 + 1,
+        ctx.buffer.line_ending,
+        if ctx.buffer.has_bom { " BOM" } else { "" },
+        ctx.buffer
+            .language_name()
+            .map(|l| format!(" {l}"))
+            .unwrap_or_default()
     );
     let offset = measure_text(loc_str.as_str(), Some(ctx.font), ctx.font_size, 1f32).width;
-    let win_width = window::screen_width();
-    let x = win_width - offset;
-    draw_rectangle(
-        x,
-        0f32,
-        offset,
-        ctx.font_size as f32,
-        color_u8!(255, 0, 0, 255),
-    );
+    let (win_w, win_h) = (screen_width(), screen_height());
+    let y = win_h - status_bar_height(ctx);
+    draw_rectangle(0f32, y, win_w, status_bar_height(ctx), ctx.theme.status_bg);
     draw_text_ex(
         loc_str.as_str(),
-        x,
-        12f32,
+        win_w - offset,
+        y + 12f32,
         TextParams {
             font_size: ctx.font_size,
-            color: color_u8!(0, 0, 0, 255),
+            color: ctx.theme.status_fg,
             font: ctx.font,
             ..Default::default()
         },
@@ -161,13 +561,12 @@ fn draw_cursor_location(ctx: &Context) {
 }
 
 fn draw_cursor_line(ctx: &Context, cursor: &Cell) {
-    let width = macroquad::window::screen_width();
     draw_rectangle(
-        0f32,
+        ctx.viewport.x,
         cursor.coord.1,
-        width,
+        ctx.viewport.w,
         ctx.font_size as f32,
-        color_u8!(255, 255, 255, 10),
+        ctx.theme.cursor_line,
     );
 }
 
@@ -178,7 +577,7 @@ pub fn draw_go_to_prompt(ctx: &Context, line: &str) {
         win_h - ctx.font_size as f32,
         win_w,
         ctx.font_size as f32,
-        color_u8!(255, 0, 0, 255),
+        ctx.theme.status_bg,
     );
     draw_text_ex(
         &format!(" Line Number: {}", line),
@@ -186,65 +585,157 @@ pub fn draw_go_to_prompt(ctx: &Context, line: &str) {
         win_h - ctx.font_size as f32,
         TextParams {
             font_size: ctx.font_size,
-            color: color_u8!(0, 0, 0, 255),
+            color: ctx.theme.status_fg,
             font: ctx.font,
             ..Default::default()
         },
     );
 }
 
+fn search_mode_label(ctx: &Context, is_case_sensitive: bool) -> String {
+    format!(
+        "{}{}{}{}",
+        if is_case_sensitive {
+            "CaseSensitive"
+        } else {
+            "CaseInSensitive"
+        },
+        if ctx.is_regex_search { ", Regex" } else { "" },
+        if ctx.is_whole_word_search {
+            ", WholeWord"
+        } else {
+            ""
+        },
+        if ctx.is_search_in_selection {
+            ", InSelection"
+        } else {
+            ""
+        }
+    )
+}
+
+fn search_status(ctx: &Context) -> String {
+    if let Some(err) = &ctx.search_error {
+        format!("[Invalid regex: {}]", err)
+    } else {
+        // '+' while the background search may still find more matches.
+        format!(
+            "[{}/{}{}]",
+            if !ctx.search_res.is_empty() {
+                ctx.last_searched_idx + 1
+            } else {
+                ctx.last_searched_idx
+            },
+            ctx.search_res.len(),
+            if ctx.search_job.is_some() { "+" } else { "" }
+        )
+    }
+}
+
 pub fn draw_find_prompt(ctx: &Context, line: &str, is_case_sensitive: bool) {
+    draw_status_message(
+        ctx,
+        &format!(
+            " Find({}): {}  {}",
+            search_mode_label(ctx, is_case_sensitive),
+            line,
+            search_status(ctx)
+        ),
+    );
+}
+
+pub fn draw_find_in_files_prompt(ctx: &Context) {
+    let status = if let Some(err) = &ctx.file_search_error {
+        format!("[Invalid regex: {}]", err)
+    } else if ctx.prompt_input != ctx.file_search_term {
+        "[Enter to search]".to_owned()
+    } else {
+        format!(
+            "[{}/{}{}]",
+            if ctx.file_search_res.is_empty() {
+                0
+            } else {
+                ctx.overlay_selected + 1
+            },
+            ctx.file_search_res.len(),
+            if ctx.file_search_job.is_some() || ctx.file_search_res.len() >= MAX_FILE_MATCHES {
+                "+"
+            } else {
+                ""
+            }
+        )
+    };
+    draw_status_message(
+        ctx,
+        &format!(
+            " FindInFiles({}) {}: {}  {}",
+            search_mode_label(ctx, ctx.is_file_search_case_sensitive),
+            ctx.file_search_root.display(),
+            ctx.prompt_input,
+            status
+        ),
+    );
+}
+
+pub fn draw_quick_open_prompt(ctx: &Context) {
+    draw_status_message(
+        ctx,
+        &format!(
+            " QuickOpen {}: {}  [{}/{}{}]",
+            ctx.quick_open_root.display(),
+            ctx.prompt_input,
+            ctx.quick_open_res.len(),
+            ctx.quick_open_files.len(),
+            if ctx.quick_open_job.is_some() {
+                "+"
+            } else {
+                ""
+            }
+        ),
+    );
+}
+
+pub fn draw_replace_prompt(ctx: &Context, is_case_sensitive: bool) {
+    // The field receiving typed text is marked with a caret.
+    let (find_caret, replace_caret) = if ctx.is_replace_input_active {
+        ("", "|")
+    } else {
+        ("|", "")
+    };
+    draw_status_message(
+        ctx,
+        &format!(
+            " Replace({}): {}{}  With: {}{}  {}",
+            search_mode_label(ctx, is_case_sensitive),
+            ctx.prompt_input,
+            find_caret,
+            ctx.replace_input,
+            replace_caret,
+            search_status(ctx)
+        ),
+    );
+}
+
+fn draw_status_message(ctx: &Context, msg: &str) {
     let (win_w, win_h) = (screen_width(), screen_height());
     draw_rectangle(
         0f32,
         win_h - ctx.font_size as f32,
         win_w,
         ctx.font_size as f32,
-        color_u8!(255, 0, 0, 255),
+        ctx.theme.status_bg,
+    );
+    draw_text_ex(
+        msg,
+        0f32,
+        win_h - ctx.font_size as f32 + 12f32,
+        TextParams {
+            font_size: ctx.font_size,
+            color: ctx.theme.status_fg,
+            font: ctx.font,
+            ..Default::default()
+        },
     );
-    if is_case_sensitive {
-        draw_text_ex(
-            &format!(
-                " Find(CaseSensitive): {}  [{}/{}]",
-                line,
-                if !ctx.search_res.is_empty() {
-                    ctx.last_searched_idx + 1
-                } else {
-                    ctx.last_searched_idx
-                },
-                ctx.search_res.len()
-            ),
-            0f32,
-            win_h - ctx.font_size as f32 + 12f32,
-            TextParams {
-                font_size: ctx.font_size,
-                color: color_u8!(0, 0, 0, 255),
-                font: ctx.font,
-                ..Default::default()
-            },
-        );
-    } else {
-        draw_text_ex(
-            &format!(
-                " Find(CaseInSensitive): {}  [{}/{}]",
-                line,
-                if !ctx.search_res.is_empty() {
-                    ctx.last_searched_idx + 1
-                } else {
-                    ctx.last_searched_idx
-                },
-                ctx.search_res.len()
-            ),
-            0f32,
-            win_h - ctx.font_size as f32 + 12f32,
-            TextParams {
-                font_size: ctx.font_size,
-                color: color_u8!(0, 0, 0, 255),
-                font: ctx.font,
-                ..Default::default()
-            },
-        );
-    }
 }
 
 pub fn draw_unsaved_prompt(ctx: &Context) {
@@ -254,15 +745,37 @@ pub fn draw_unsaved_prompt(ctx: &Context) {
         win_h - ctx.font_size as f32,
         win_w,
         ctx.font_size as f32,
-        color_u8!(255, 0, 0, 255),
+        ctx.theme.status_bg,
+    );
+    draw_text_ex(
+        "File is modified! Are you sure to discard unsaved changes?[Press 'y' to discard, press 'n' to cancel!]",
+        0f32,
+        win_h - ctx.font_size as f32 + 12f32,
+        TextParams {
+            font_size: ctx.font_size,
+            color: ctx.theme.status_fg,
+            font: ctx.font,
+            ..Default::default()
+        },
+    );
+}
+
+pub fn draw_recover_prompt(ctx: &Context) {
+    let (win_w, win_h) = (screen_width(), screen_height());
+    draw_rectangle(
+        0f32,
+        win_h - ctx.font_size as f32,
+        win_w,
+        ctx.font_size as f32,
+        ctx.theme.status_bg,
     );
     draw_text_ex(
-        "File is modified! Are you sure to quit?[Press 'y' to exit without saving, press 'n' to not exit!]",
+        "Swap file with unsaved changes found![Press 'r' to recover, 'd' to show diff, 'x' to discard it!]",
         0f32,
         win_h - ctx.font_size as f32 + 12f32,
         TextParams {
             font_size: ctx.font_size,
-            color: color_u8!(0, 0, 0, 255),
+            color: ctx.theme.status_fg,
             font: ctx.font,
             ..Default::default()
         },
@@ -270,23 +783,20 @@ pub fn draw_unsaved_prompt(ctx: &Context) {
 }
 
 fn draw_eof_indicator(ctx: &Context) {
-    if (ctx.vert_cell_count.0 + ctx.vert_cell_count.1) > ctx.buffer.buf.lines().count() {
-        let y = ctx.cells.iter().last().unwrap().coord.1 + ctx.font_size as f32;
+    let last = ctx.cells.iter().last().unwrap();
+    // Shown once the final '\n' is laid out, wrapped rows can push it off the pane.
+    if ctx.vert_cell_count.0 + last.pos.1 + 1 == ctx.buffer.line_count() && last.c == '\n' {
+        let y = last.coord.1 + ctx.font_size as f32;
         let w = measure_text("EOF", Some(ctx.font), ctx.font_size, 1f32).width;
-        draw_rectangle(
-            0f32,
-            y,
-            w,
-            ctx.font_size as f32,
-            color_u8!(255, 255, 255, 100),
-        );
+        let x = ctx.viewport.x + gutter_width(ctx, &ctx.buffer);
+        draw_rectangle(x, y, w, ctx.font_size as f32, ctx.theme.eof_bg);
         draw_text_ex(
             "EOF",
-            0f32,
+            x,
             y + 12f32,
             TextParams {
                 font_size: ctx.font_size,
-                color: color_u8!(0, 0, 0, 255),
+                color: ctx.theme.eof_fg,
                 font: ctx.font,
                 ..Default::default()
             },
@@ -294,59 +804,222 @@ fn draw_eof_indicator(ctx: &Context) {
     }
 }
 
+fn is_searching(ctx: &Context) -> bool {
+    matches!(
+        ctx.mode,
+        Modes::FindCaseSensitive
+            | Modes::FindCaseInSensitive
+            | Modes::ReplaceCaseSensitive
+            | Modes::ReplaceCaseInSensitive
+    )
+}
+
+// Both the cells and the matches are sorted by position, so one pass over the
+// cells is enough to paint every visible match.
+fn draw_search_matches(ctx: &Context) {
+    let first_line = ctx.vert_cell_count.0;
+    let mut i = ctx
+        .search_res
+        .partition_point(|&(_, _, end)| (end.1, end.0) <= (first_line, 0));
+    for cell in ctx.cells.iter() {
+        let pos = (first_line + cell.pos.1, cell.pos.0);
+        while i < ctx.search_res.len() {
+            let end = ctx.search_res[i].2;
+            if (end.1, end.0) > pos {
+                break;
+            }
+            i += 1;
+        }
+        let Some(&(idx, start, _)) = ctx.search_res.get(i) else {
+            break;
+        };
+        if (start.1, start.0) <= pos {
+            let color = if idx == ctx.last_searched_idx {
+                ctx.theme.search_current
+            } else {
+                ctx.theme.search
+            };
+            draw_rectangle(
+                cell.coord.0,
+                cell.coord.1,
+                cell.bound.0,
+                cell.bound.1,
+                color,
+            );
+        }
+    }
+}
+
+fn draw_scrollbar(ctx: &Context) {
+    let width = SCROLLBAR_WIDTH;
+    let area = ctx.viewport;
+    let x = area.x + area.w - width;
+    let lines = ctx.buffer.line_count().max(1) as f32;
+    draw_rectangle(x, area.y, width, area.h, ctx.theme.scrollbar_track);
+    let thumb_y = area.y + area.h * ctx.vert_cell_count.0 as f32 / lines;
+    let thumb_h = (area.h * ctx.vert_cell_count.1 as f32 / lines).clamp(4f32, area.h);
+    draw_rectangle(x, thumb_y, width, thumb_h, ctx.theme.scrollbar);
+    if !is_searching(ctx) {
+        return;
+    }
+    // Several matches usually land on the same pixel row, draw each row once.
+    let mut last_y = -1f32;
+    for &(idx, start, _) in ctx.search_res.iter() {
+        let y = (area.y + area.h * start.1 as f32 / lines).floor();
+        if y == last_y && idx != ctx.last_searched_idx {
+            continue;
+        }
+        last_y = y;
+        let color = if idx == ctx.last_searched_idx {
+            ctx.theme.search_current
+        } else {
+            ctx.theme.search
+        };
+        draw_rectangle(x, y, width, 2f32, Color { a: 1f32, ..color });
+    }
+}
+
+type SelectionEnds = ((usize, usize), (usize, usize));
+
+// (line, column) of both ends of the inclusive selection offsets, looked up once
+// per frame rather than per cell.
+fn selection_ends(buffer: &Buffer, (start, end): (usize, usize)) -> SelectionEnds {
+    (
+        buffer.offset_to_line_col(start),
+        buffer.offset_to_line_col(end),
+    )
+}
+
+// Whether the cell at pos lies between the selection ends. Compared as (line, column)
+// so an end scrolled out of the view needs no cell of its own.
+fn is_selected((start, end): SelectionEnds, first_line: usize, pos: (usize, usize)) -> bool {
+    let at = (first_line + pos.1, pos.0);
+    start <= at && at <= end
+}
+
+// The view rows stored with the selection go stale once the view scrolls, only
+// its buffer offsets are used.
 fn draw_selection(ctx: &Context) {
-    let end = ctx
-        .cells
-        .iter()
-        .enumerate()
-        .find(|&(_, c)| c.pos == ctx.selection_range.unwrap().1 .1)
-        .unwrap()
-        .0;
-    let start = ctx
+    let Some(((a, _), (b, _))) = ctx.selection_range else {
+        return;
+    };
+    let first = ctx.vert_cell_count.0;
+    let ends = selection_ends(&ctx.buffer, (a.min(b), a.max(b)));
+    for c in ctx.cells.iter() {
+        c.bg_color.set(if is_selected(ends, first, c.pos) {
+            ctx.theme.selection
+        } else {
+            ctx.theme.bg
+        });
+    }
+}
+
+// Where a block column starts on a visible line, columns past the line end are
+// counted on from its '\n'.
+fn block_column_at(ctx: &Context, line: usize, col: usize) -> Option<(f32, f32)> {
+    let first = ctx.vert_cell_count.0;
+    let cells: Vec<&Cell> = ctx
         .cells
         .iter()
-        .enumerate()
-        .find(|&(_, c)| c.pos == ctx.selection_range.unwrap().0 .1)
-        .unwrap()
-        .0;
-    if start < end {
-        for c in &ctx.cells[..start] {
-            c.bg_color.set(ctx.bg_color);
-        }
-        for c in &ctx.cells[start..=end] {
-            c.bg_color.set(ctx.selection_col);
+        .filter(|c| first + c.pos.1 == line)
+        .collect();
+    if let Some(c) = cells.iter().find(|c| c.pos.0 == col && c.c != '\n') {
+        return Some((c.coord.0, c.coord.1));
+    }
+    let eol = cells.iter().find(|c| c.c == '\n')?;
+    let past = col.saturating_sub(eol.pos.0) as f32;
+    Some((eol.coord.0 + past * column_width(ctx), eol.coord.1))
+}
+
+// The block selection as a rectangle over the text, a block with no width is
+// drawn as a thin line down its column.
+fn draw_block_selection(ctx: &Context) {
+    let Some(((l0, c0), (l1, c1))) = ctx.block_selection else {
+        return;
+    };
+    let (left, right) = (c0.min(c1), c0.max(c1));
+    for line in l0.min(l1)..=l0.max(l1) {
+        let (Some(start), Some(end)) = (
+            block_column_at(ctx, line, left),
+            block_column_at(ctx, line, right),
+        ) else {
+            continue;
+        };
+        // A block row split by soft wrap only shows its first part.
+        let end_x = if end.1 == start.1 { end.0 } else { start.0 };
+        if left == right {
+            draw_rectangle(
+                start.0,
+                start.1,
+                2f32,
+                ctx.font_size as f32,
+                ctx.theme.cursor,
+            );
+        } else {
+            draw_rectangle(
+                start.0,
+                start.1,
+                end_x - start.0,
+                ctx.font_size as f32,
+                ctx.theme.selection,
+            );
         }
-        if ctx.cells.get(end + 1).is_some() {
-            for c in &ctx.cells[end + 1..] {
-                c.bg_color.set(ctx.bg_color);
+    }
+}
+
+// Extra cursors and their selections, drawn over the text.
+fn draw_extra_cursors(ctx: &Context) {
+    let first = ctx.vert_cell_count.0;
+    for cursor in ctx.extra_cursors.iter() {
+        if let Some(sel) = cursor.selection {
+            let ends = selection_ends(&ctx.buffer, sel);
+            for c in ctx.cells.iter() {
+                if is_selected(ends, first, c.pos) {
+                    draw_rectangle(
+                        c.coord.0,
+                        c.coord.1,
+                        c.bound.0,
+                        c.bound.1,
+                        ctx.theme.selection,
+                    );
+                }
             }
         }
-    } else {
-        for c in &ctx.cells[..end] {
-            c.bg_color.set(ctx.bg_color);
+        let (line, col) = ctx.buffer.offset_to_line_col(cursor.off);
+        if line < first {
+            continue;
         }
-        for c in &ctx.cells[end..=start] {
-            c.bg_color.set(ctx.selection_col);
-        }
-        if ctx.cells.get(start + 1).is_some() {
-            for c in &ctx.cells[start + 1..] {
-                c.bg_color.set(ctx.bg_color);
-            }
+        if let Some(c) = ctx.cells.iter().find(|c| c.pos == (col, line - first)) {
+            draw_rectangle(c.coord.0, c.coord.1, c.bound.0, c.bound.1, ctx.theme.cursor);
         }
     }
 }
 
 pub async fn render(ctx: &Context) {
-    clear_background(ctx.bg_color);
+    clear_background(ctx.theme.bg);
+    for (id, rect) in pane_rects(ctx) {
+        if id != ctx.active_pane {
+            draw_inactive_pane(ctx, &ctx.panes[id], rect);
+        }
+    }
+    set_clip(Some(ctx.viewport));
+    draw_gutter(
+        ctx,
+        &ctx.buffer,
+        &ctx.cells,
+        ctx.vert_cell_count.0,
+        ctx.viewport,
+        ctx.vert_cell_count.0 + ctx.curr_cursor_pos.1,
+    );
+    set_clip(Some(text_rect(ctx, &ctx.buffer, ctx.viewport)));
     if ctx.eof_indicator {
         draw_eof_indicator(ctx);
     }
-    let cursor_to_render = ctx
-        .cells
-        .iter()
-        .filter(|c| c.pos == ctx.curr_cursor_pos)
-        .next()
-        .unwrap();
+    // Only missing when a wrapped line is taller than the whole pane.
+    let cursor_to_render = ctx.cells.iter().find(|c| c.pos == ctx.curr_cursor_pos);
+    if ctx.selection_range.is_some() {
+        draw_selection(ctx);
+    }
     for cell in ctx.cells.iter() {
         draw_rectangle(
             cell.coord.0,
@@ -355,12 +1028,10 @@ pub async fn render(ctx: &Context) {
             cell.bound.1,
             cell.bg_color.get(),
         );
-        if ctx.selection_range.is_some() {
-            draw_selection(ctx);
-        } else {
-            cell.bg_color.set(ctx.bg_color);
+        if ctx.selection_range.is_none() {
+            cell.bg_color.set(ctx.theme.bg);
         }
-        if cell.c == '\n' {
+        if cell.c == '\n' || cell.c == '\r' {
             continue;
         }
         draw_text_ex(
@@ -369,40 +1040,77 @@ pub async fn render(ctx: &Context) {
             cell.coord.1 + 12f32,
             TextParams {
                 font_size: ctx.font_size,
-                color: ctx.font_color,
+                color: cell.fg_color,
                 font: ctx.font,
                 ..Default::default()
             },
         );
     }
-    if ctx.is_cursorline {
-        draw_cursor_line(ctx, cursor_to_render);
+    if is_searching(ctx) && !ctx.search_res.is_empty() {
+        draw_search_matches(ctx);
     }
-    draw_rectangle(
-        cursor_to_render.coord.0,
-        cursor_to_render.coord.1,
-        cursor_to_render.bound.0,
-        cursor_to_render.bound.1,
-        ctx.cursor_col,
-    );
-    draw_cursor_location(ctx);
+    if let Some(cursor) = cursor_to_render {
+        if ctx.is_cursorline {
+            draw_cursor_line(ctx, cursor);
+        }
+        draw_rectangle(
+            cursor.coord.0,
+            cursor.coord.1,
+            cursor.bound.0,
+            cursor.bound.1,
+            ctx.theme.cursor,
+        );
+    }
+    draw_extra_cursors(ctx);
+    draw_block_selection(ctx);
+    set_clip(Some(ctx.viewport));
+    draw_scrollbar(ctx);
+    set_clip(None);
+    if ctx.panes.len() > 1 {
+        draw_pane_borders(ctx);
+    }
+    draw_tab_bar(ctx);
+    draw_status_bar(ctx);
     if ctx.mode == Modes::GoToLine {
         draw_go_to_prompt(ctx, &ctx.prompt_input);
     } else if ctx.mode == Modes::FindCaseSensitive {
         draw_find_prompt(ctx, &ctx.prompt_input, true);
     } else if ctx.mode == Modes::FindCaseInSensitive {
         draw_find_prompt(ctx, &ctx.prompt_input, false);
+    } else if ctx.mode == Modes::ReplaceCaseSensitive {
+        draw_replace_prompt(ctx, true);
+    } else if ctx.mode == Modes::ReplaceCaseInSensitive {
+        draw_replace_prompt(ctx, false);
     } else if ctx.mode == Modes::ModifiedPrompt {
         draw_unsaved_prompt(ctx);
     } else if ctx.mode == Modes::ShowHelp {
         render_help_page(ctx);
+    } else if ctx.mode == Modes::RecoverPrompt {
+        draw_recover_prompt(ctx);
+    } else if ctx.mode == Modes::ShowSwapDiff {
+        render_overlay(ctx, None);
+    } else if ctx.mode == Modes::BufferList {
+        render_overlay(ctx, Some(ctx.overlay_selected));
+    } else if ctx.mode == Modes::ThemeList {
+        render_overlay(ctx, Some(ctx.overlay_selected));
+        if let Some(msg) = &ctx.status_msg {
+            draw_status_message(ctx, msg);
+        }
+    } else if ctx.mode == Modes::FindInFiles {
+        render_overlay(ctx, Some(ctx.overlay_selected));
+        draw_find_in_files_prompt(ctx);
+    } else if ctx.mode == Modes::QuickOpen {
+        render_overlay(ctx, Some(ctx.overlay_selected));
+        draw_quick_open_prompt(ctx);
+    } else if let Some(msg) = &ctx.status_msg {
+        draw_status_message(ctx, msg);
     }
     next_frame().await
 }
 
 fn render_help_page(ctx: &Context) {
     let (win_w, win_h) = (screen_width(), screen_height());
-    draw_rectangle(0f32, 0f32, win_w, win_h, color_u8!(0, 0, 0, 255));
+    draw_rectangle(0f32, 0f32, win_w, win_h, ctx.theme.overlay_bg);
     let mut y = 0f32;
     HELP_PAGE.lines().enumerate().for_each(|(i, l)| {
         y = (i * ctx.font_size as usize) as f32;
@@ -412,10 +1120,121 @@ fn render_help_page(ctx: &Context) {
             y,
             TextParams {
                 font_size: ctx.font_size,
-                color: color_u8!(255, 255, 255, 255),
+                color: ctx.theme.overlay_fg,
                 font: ctx.font,
                 ..Default::default()
             },
         );
     });
 }
+
+fn render_overlay(ctx: &Context, selected: Option<usize>) {
+    let (win_w, win_h) = (screen_width(), screen_height());
+    draw_rectangle(0f32, 0f32, win_w, win_h, ctx.theme.overlay_bg);
+    let rows = win_h as usize / ctx.font_size as usize;
+    for (i, l) in ctx
+        .overlay_lines
+        .iter()
+        .skip(ctx.overlay_scroll)
+        .take(rows)
+        .enumerate()
+    {
+        if selected == Some(i + ctx.overlay_scroll) {
+            draw_rectangle(
+                0f32,
+                (i * ctx.font_size as usize) as f32,
+                win_w,
+                ctx.font_size as f32,
+                ctx.theme.selection,
+            );
+        }
+        let color = if l.starts_with('+') {
+            ctx.theme.diff_added
+        } else if l.starts_with('-') {
+            ctx.theme.diff_removed
+        } else {
+            ctx.theme.overlay_fg
+        };
+        draw_text_ex(
+            l,
+            0f32,
+            (i * ctx.font_size as usize) as f32 + 12f32,
+            TextParams {
+                font_size: ctx.font_size,
+                color,
+                font: ctx.font,
+                ..Default::default()
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selection_taller_than_the_view_needs_no_visible_ends() {
+        let text: String = (0..200).map(|i| format!("line {i}\n")).collect();
+        let buffer = Buffer::from_text(&text);
+        // From line 10 to line 120 while lines 100..140 are shown.
+        let sel = (buffer.line_to_char(10) + 2, buffer.line_to_char(120) + 3);
+        let ends = selection_ends(&buffer, sel);
+        assert_eq!(ends, ((10, 2), (120, 3)));
+        assert!(is_selected(ends, 100, (0, 0)));
+        assert!(is_selected(ends, 100, (3, 20)));
+        assert!(!is_selected(ends, 100, (4, 20)));
+        assert!(!is_selected(ends, 100, (0, 39)));
+        assert!(!is_selected(ends, 0, (1, 10)));
+    }
+
+    #[test]
+    fn wrap_starts_breaks_after_whitespace() {
+        let chars: Vec<char> = "aaa bbb ccc".chars().collect();
+        let advances: Vec<(f32, f32)> = (0..chars.len()).map(|i| (i as f32, 1f32)).collect();
+        assert_eq!(wrap_starts(&chars, &advances, 5f32), vec![0, 4, 8]);
+    }
+
+    #[test]
+    fn wrap_starts_splits_words_longer_than_the_width() {
+        let chars: Vec<char> = "abcdefgh".chars().collect();
+        let advances: Vec<(f32, f32)> = (0..chars.len()).map(|i| (i as f32, 1f32)).collect();
+        assert_eq!(wrap_starts(&chars, &advances, 3f32), vec![0, 3, 6]);
+    }
+
+    #[test]
+    fn wrap_starts_of_empty_line() {
+        assert_eq!(wrap_starts(&[], &[], 10f32), vec![0]);
+    }
+
+    #[test]
+    fn wrap_starts_with_wide_chars() {
+        // Double width CJK chars, two fit in a row of width 4.
+        let chars: Vec<char> = "日本語文字".chars().collect();
+        let advances: Vec<(f32, f32)> = (0..chars.len()).map(|i| (i as f32 * 2f32, 2f32)).collect();
+        assert_eq!(wrap_starts(&chars, &advances, 4f32), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn wrap_starts_ignores_the_line_break() {
+        let chars: Vec<char> = "ab\n".chars().collect();
+        let advances: Vec<(f32, f32)> = (0..chars.len()).map(|i| (i as f32, 1f32)).collect();
+        assert_eq!(wrap_starts(&chars, &advances, 2f32), vec![0]);
+    }
+
+    #[test]
+    fn line_numbers_absolute_and_relative() {
+        assert_eq!(line_number(0, 5, false), 1);
+        assert_eq!(line_number(5, 5, true), 6);
+        assert_eq!(line_number(2, 5, true), 3);
+        assert_eq!(line_number(9, 5, true), 4);
+    }
+
+    #[test]
+    fn gutter_fits_the_last_line_number() {
+        assert_eq!(gutter_digits(0), 2);
+        assert_eq!(gutter_digits(99), 2);
+        assert_eq!(gutter_digits(100), 3);
+        assert_eq!(gutter_digits(12_345), 5);
+    }
+}
@@ -0,0 +1,495 @@
+use std::{fmt, ops::Range, sync::Arc};
+
+// Leaves are kept below this size so in-place edits inside a leaf stay cheap.
+const MAX_LEAF_BYTES: usize = 1024;
+
+// Children are shared, so cloning a rope is cheap and an edit only copies the
+// nodes on its path.
+#[derive(Debug, Clone)]
+enum Kind {
+    Leaf(String),
+    Branch(Arc<Node>, Arc<Node>),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    chars: usize,
+    newlines: usize,
+    height: usize,
+    kind: Kind,
+}
+
+fn char_to_byte(s: &str, idx: usize) -> usize {
+    s.char_indices().nth(idx).map_or(s.len(), |(b, _)| b)
+}
+
+impl Node {
+    fn leaf(s: String) -> Node {
+        Node {
+            chars: s.chars().count(),
+            newlines: s.bytes().filter(|&b| b == b'\n').count(),
+            height: 0,
+            kind: Kind::Leaf(s),
+        }
+    }
+
+    fn branch(l: Node, r: Node) -> Node {
+        Node {
+            chars: l.chars + r.chars,
+            newlines: l.newlines + r.newlines,
+            height: 1 + std::cmp::max(l.height, r.height),
+            kind: Kind::Branch(Arc::new(l), Arc::new(r)),
+        }
+    }
+
+    fn empty() -> Node {
+        Node::leaf(String::new())
+    }
+
+    fn from_leaves(mut leaves: Vec<Node>) -> Node {
+        match leaves.len() {
+            0 => Node::empty(),
+            1 => leaves.pop().unwrap(),
+            n => {
+                let right = leaves.split_off(n / 2);
+                Node::branch(Node::from_leaves(leaves), Node::from_leaves(right))
+            }
+        }
+    }
+
+    fn from_str(s: &str) -> Node {
+        let mut leaves = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let mut end = std::cmp::min(MAX_LEAF_BYTES, rest.len());
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            leaves.push(Node::leaf(rest[..end].to_owned()));
+            rest = &rest[end..];
+        }
+        Node::from_leaves(leaves)
+    }
+
+    // Takes the node out of a child, copying it when another rope still shares it.
+    fn unshare(node: Arc<Node>) -> Node {
+        Arc::try_unwrap(node).unwrap_or_else(|n| (*n).clone())
+    }
+
+    fn into_children(self) -> (Node, Node) {
+        match self.kind {
+            Kind::Branch(l, r) => (Node::unshare(l), Node::unshare(r)),
+            Kind::Leaf(_) => unreachable!("leaf node has no children"),
+        }
+    }
+
+    fn join(a: Node, b: Node) -> Node {
+        if a.chars == 0 {
+            return b;
+        }
+        if b.chars == 0 {
+            return a;
+        }
+        match (a, b) {
+            (
+                Node {
+                    kind: Kind::Leaf(mut x),
+                    ..
+                },
+                Node {
+                    kind: Kind::Leaf(y),
+                    ..
+                },
+            ) if x.len() + y.len() <= MAX_LEAF_BYTES => {
+                x.push_str(&y);
+                Node::leaf(x)
+            }
+            (a, b) => {
+                if a.height > b.height + 1 {
+                    let (l, r) = a.into_children();
+                    Node::balance(l, Node::join(r, b))
+                } else if b.height > a.height + 1 {
+                    let (l, r) = b.into_children();
+                    Node::balance(Node::join(a, l), r)
+                } else {
+                    Node::branch(a, b)
+                }
+            }
+        }
+    }
+
+    fn balance(l: Node, r: Node) -> Node {
+        if l.height > r.height + 1 {
+            let (ll, lr) = l.into_children();
+            if ll.height >= lr.height {
+                Node::branch(ll, Node::branch(lr, r))
+            } else {
+                let (lrl, lrr) = lr.into_children();
+                Node::branch(Node::branch(ll, lrl), Node::branch(lrr, r))
+            }
+        } else if r.height > l.height + 1 {
+            let (rl, rr) = r.into_children();
+            if rr.height >= rl.height {
+                Node::branch(Node::branch(l, rl), rr)
+            } else {
+                let (rll, rlr) = rl.into_children();
+                Node::branch(Node::branch(l, rll), Node::branch(rlr, rr))
+            }
+        } else {
+            Node::branch(l, r)
+        }
+    }
+
+    fn split(self, idx: usize) -> (Node, Node) {
+        match self.kind {
+            Kind::Leaf(mut s) => {
+                let right = s.split_off(char_to_byte(&s, idx));
+                (Node::leaf(s), Node::leaf(right))
+            }
+            Kind::Branch(l, r) => {
+                if idx <= l.chars {
+                    let (a, b) = Node::unshare(l).split(idx);
+                    (a, Node::join(b, Node::unshare(r)))
+                } else {
+                    let (a, b) = Node::unshare(r).split(idx - l.chars);
+                    (Node::join(Node::unshare(l), a), b)
+                }
+            }
+        }
+    }
+
+    fn update(&mut self) {
+        if let Kind::Branch(l, r) = &self.kind {
+            self.chars = l.chars + r.chars;
+            self.newlines = l.newlines + r.newlines;
+        }
+    }
+
+    fn insert_in_place(&mut self, idx: usize, s: &str) -> bool {
+        let ok = match &mut self.kind {
+            Kind::Leaf(leaf) => {
+                if leaf.len() + s.len() > MAX_LEAF_BYTES {
+                    return false;
+                }
+                leaf.insert_str(char_to_byte(leaf, idx), s);
+                *self = Node::leaf(std::mem::take(leaf));
+                return true;
+            }
+            Kind::Branch(l, r) => {
+                if idx <= l.chars {
+                    Arc::make_mut(l).insert_in_place(idx, s)
+                } else {
+                    let off = l.chars;
+                    Arc::make_mut(r).insert_in_place(idx - off, s)
+                }
+            }
+        };
+        if ok {
+            self.update();
+        }
+        ok
+    }
+
+    fn remove_in_place(&mut self, start: usize, end: usize) -> bool {
+        let ok = match &mut self.kind {
+            Kind::Leaf(leaf) => {
+                let (b_start, b_end) = (char_to_byte(leaf, start), char_to_byte(leaf, end));
+                leaf.replace_range(b_start..b_end, "");
+                *self = Node::leaf(std::mem::take(leaf));
+                return true;
+            }
+            Kind::Branch(l, r) => {
+                if end <= l.chars {
+                    Arc::make_mut(l).remove_in_place(start, end)
+                } else if start >= l.chars {
+                    let off = l.chars;
+                    Arc::make_mut(r).remove_in_place(start - off, end - off)
+                } else {
+                    false
+                }
+            }
+        };
+        if ok {
+            self.update();
+        }
+        ok
+    }
+
+    fn char(&self, idx: usize) -> Option<char> {
+        match &self.kind {
+            Kind::Leaf(s) => s.chars().nth(idx),
+            Kind::Branch(l, r) => {
+                if idx < l.chars {
+                    l.char(idx)
+                } else {
+                    r.char(idx - l.chars)
+                }
+            }
+        }
+    }
+
+    fn newlines_before(&self, idx: usize) -> usize {
+        match &self.kind {
+            Kind::Leaf(s) => s.chars().take(idx).filter(|&c| c == '\n').count(),
+            Kind::Branch(l, r) => {
+                if idx < l.chars {
+                    l.newlines_before(idx)
+                } else {
+                    l.newlines + r.newlines_before(idx - l.chars)
+                }
+            }
+        }
+    }
+
+    // Char index just past the n-th newline, n starting from 1.
+    fn after_newline(&self, n: usize) -> usize {
+        match &self.kind {
+            Kind::Leaf(s) => {
+                let mut seen = 0;
+                for (i, c) in s.chars().enumerate() {
+                    if c == '\n' {
+                        seen += 1;
+                        if seen == n {
+                            return i + 1;
+                        }
+                    }
+                }
+                self.chars
+            }
+            Kind::Branch(l, r) => {
+                if n <= l.newlines {
+                    l.after_newline(n)
+                } else {
+                    l.chars + r.after_newline(n - l.newlines)
+                }
+            }
+        }
+    }
+
+    fn collect(&self, start: usize, end: usize, out: &mut String) {
+        if start >= end {
+            return;
+        }
+        match &self.kind {
+            Kind::Leaf(s) => {
+                out.push_str(&s[char_to_byte(s, start)..char_to_byte(s, end)]);
+            }
+            Kind::Branch(l, r) => {
+                if start < l.chars {
+                    l.collect(start, std::cmp::min(end, l.chars), out);
+                }
+                if end > l.chars {
+                    r.collect(start.saturating_sub(l.chars), end - l.chars, out);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rope {
+    root: Node,
+}
+
+impl Default for Rope {
+    fn default() -> Self {
+        Rope {
+            root: Node::empty(),
+        }
+    }
+}
+
+impl From<&str> for Rope {
+    fn from(s: &str) -> Self {
+        Rope {
+            root: Node::from_str(s),
+        }
+    }
+}
+
+impl Rope {
+    pub fn len_chars(&self) -> usize {
+        self.root.chars
+    }
+
+    pub fn len_newlines(&self) -> usize {
+        self.root.newlines
+    }
+
+    pub fn char(&self, idx: usize) -> Option<char> {
+        self.root.char(idx)
+    }
+
+    pub fn insert(&mut self, idx: usize, s: &str) {
+        assert!(idx <= self.len_chars(), "Rope insert index out of bounds!");
+        if s.is_empty() || self.root.insert_in_place(idx, s) {
+            return;
+        }
+        let root = std::mem::replace(&mut self.root, Node::empty());
+        let (a, b) = root.split(idx);
+        self.root = Node::join(Node::join(a, Node::from_str(s)), b);
+    }
+
+    pub fn remove(&mut self, range: Range<usize>) {
+        assert!(
+            range.start <= range.end && range.end <= self.len_chars(),
+            "Rope remove range out of bounds!"
+        );
+        if range.is_empty() || self.root.remove_in_place(range.start, range.end) {
+            return;
+        }
+        let root = std::mem::replace(&mut self.root, Node::empty());
+        let (a, rest) = root.split(range.start);
+        let (_, c) = rest.split(range.end - range.start);
+        self.root = Node::join(a, c);
+    }
+
+    pub fn slice(&self, range: Range<usize>) -> String {
+        let mut out = String::new();
+        self.root.collect(
+            range.start,
+            std::cmp::min(range.end, self.len_chars()),
+            &mut out,
+        );
+        out
+    }
+
+    pub fn char_to_line(&self, idx: usize) -> usize {
+        self.root.newlines_before(idx)
+    }
+
+    pub fn line_to_char(&self, line: usize) -> usize {
+        if line == 0 {
+            0
+        } else if line > self.len_newlines() {
+            self.len_chars()
+        } else {
+            self.root.after_newline(line)
+        }
+    }
+
+    pub fn chunks(&self) -> Chunks<'_> {
+        Chunks {
+            stack: vec![&self.root],
+        }
+    }
+}
+
+impl fmt::Display for Rope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for chunk in self.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Chunks<'a> {
+    stack: Vec<&'a Node>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(node) = self.stack.pop() {
+            match &node.kind {
+                Kind::Leaf(s) if s.is_empty() => continue,
+                Kind::Leaf(s) => return Some(s),
+                Kind::Branch(l, r) => {
+                    self.stack.push(r);
+                    self.stack.push(l);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mixes one, two, three and four byte chars so leaves get split off at every
+    // kind of char boundary.
+    fn sample(len: usize) -> String {
+        ['a', '\n', 'é', '日', '🦀', ' ']
+            .iter()
+            .cycle()
+            .take(len)
+            .collect()
+    }
+
+    #[test]
+    fn empty_rope() {
+        let rope = Rope::default();
+        assert_eq!(rope.len_chars(), 0);
+        assert_eq!(rope.len_newlines(), 0);
+        assert_eq!(rope.char(0), None);
+        assert_eq!(rope.line_to_char(0), 0);
+        assert_eq!(rope.line_to_char(1), 0);
+        assert_eq!(rope.char_to_line(0), 0);
+        assert_eq!(rope.slice(0..10), "");
+        assert_eq!(rope.chunks().count(), 0);
+    }
+
+    #[test]
+    fn last_line_without_newline() {
+        let rope = Rope::from("ab\ncd");
+        assert_eq!(rope.len_newlines(), 1);
+        assert_eq!(rope.line_to_char(1), 3);
+        assert_eq!(rope.line_to_char(2), 5);
+        assert_eq!(rope.char_to_line(5), 1);
+        assert_eq!(rope.slice(3..5), "cd");
+    }
+
+    #[test]
+    fn multibyte_text_spanning_many_leaves() {
+        let text = sample(5000);
+        let rope = Rope::from(text.as_str());
+        assert!(rope.chunks().count() > 1);
+        assert_eq!(rope.to_string(), text);
+        assert_eq!(rope.len_chars(), 5000);
+        assert_eq!(rope.len_newlines(), text.matches('\n').count());
+        assert_eq!(rope.char(4), Some('🦀'));
+        assert_eq!(rope.slice(2..5), "é日🦀");
+        // Line 100 starts right after the 100th '\n', which is every sixth char.
+        assert_eq!(rope.line_to_char(100), 100 * 6 - 4);
+        assert_eq!(rope.char_to_line(100 * 6 - 4), 100);
+    }
+
+    #[test]
+    fn edits_match_a_plain_string() {
+        let mut rope = Rope::from(sample(3000).as_str());
+        let mut model: Vec<char> = sample(3000).chars().collect();
+        let mut seed = 7usize;
+        for i in 0..300 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345) % (1 << 31);
+            let at = seed % (model.len() + 1);
+            if i % 3 == 0 {
+                let end = std::cmp::min(at + seed % 1500, model.len());
+                rope.remove(at..end);
+                model.drain(at..end);
+            } else {
+                let s = sample(seed % 700);
+                rope.insert(at, &s);
+                model.splice(at..at, s.chars());
+            }
+            assert_eq!(rope.len_chars(), model.len());
+        }
+        assert_eq!(rope.to_string(), model.iter().collect::<String>());
+        let newlines = model.iter().filter(|&&c| c == '\n').count();
+        assert_eq!(rope.len_newlines(), newlines);
+    }
+
+    #[test]
+    fn clones_are_not_changed_by_edits() {
+        let text = sample(4000);
+        let mut rope = Rope::from(text.as_str());
+        let snapshot = rope.clone();
+        rope.insert(10, "xyz");
+        rope.remove(2000..3500);
+        assert_eq!(snapshot.to_string(), text);
+        assert_eq!(rope.len_chars(), 4000 + 3 - 1500);
+    }
+}
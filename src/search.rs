@@ -0,0 +1,456 @@
+use std::{
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    thread,
+};
+
+use ignore::WalkBuilder;
+use regex::{Regex, RegexBuilder};
+
+use crate::rope::Rope;
+
+// Start (col, line) and exclusive end (col, line) of a match.
+pub type Match = ((usize, usize), (usize, usize));
+
+// Matches found so far are handed over in batches of this size.
+const BATCH_SIZE: usize = 4096;
+
+// Searching in files stops after this many matches.
+pub const MAX_FILE_MATCHES: usize = 10_000;
+
+// Files with a NUL byte in their first block are treated as binary, like git does.
+const BINARY_CHECK_BYTES: usize = 8000;
+
+#[derive(Clone)]
+pub struct Query {
+    pub pattern: String,
+    pub is_case_sensitive: bool,
+    pub is_regex: bool,
+    pub is_whole_word: bool,
+    // Char range the matches have to lie in.
+    pub scope: Option<(usize, usize)>,
+}
+
+pub enum SearchMsg {
+    Matches(Vec<Match>),
+    Error(String),
+    Done,
+}
+
+// Plain text is searched as an escaped pattern, so both modes share one engine.
+pub fn build_regex(query: &Query) -> Result<Regex, regex::Error> {
+    let pattern = if query.is_regex {
+        query.pattern.clone()
+    } else {
+        regex::escape(&query.pattern)
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(!query.is_case_sensitive)
+        .multi_line(true)
+        .build()
+}
+
+// Syntax errors span several lines, the last one says what is wrong.
+pub fn regex_error_message(e: &regex::Error) -> String {
+    let msg = e.to_string();
+    msg.lines()
+        .rfind(|l| !l.trim().is_empty())
+        .unwrap_or("invalid pattern")
+        .trim()
+        .to_owned()
+}
+
+// Walks forward through the text turning byte offsets into (col, line) positions,
+// only counting the chars since the previous offset. Offsets must be passed in
+// ascending order.
+struct PosTracker<'a> {
+    text: &'a str,
+    byte: usize,
+    col: usize,
+    line: usize,
+    line_start: usize,
+}
+
+impl<'a> PosTracker<'a> {
+    fn new(text: &'a str) -> Self {
+        PosTracker {
+            text,
+            byte: 0,
+            col: 0,
+            line: 0,
+            line_start: 0,
+        }
+    }
+
+    fn pos(&mut self, byte: usize) -> (usize, usize) {
+        for (i, c) in self.text[self.byte..byte].char_indices() {
+            if c == '\n' {
+                self.line += 1;
+                self.col = 0;
+                self.line_start = self.byte + i + 1;
+            } else {
+                self.col += 1;
+            }
+        }
+        self.byte = byte;
+        (self.col, self.line)
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn char_to_byte(text: &str, idx: usize) -> usize {
+    text.char_indices().nth(idx).map_or(text.len(), |(b, _)| b)
+}
+
+// Byte range of every match in order, after the whole word and scope filters.
+// Stops early when f returns false.
+pub fn for_each_match(
+    text: &str,
+    query: &Query,
+    f: &mut dyn FnMut(Range<usize>) -> bool,
+) -> Result<(), regex::Error> {
+    let re = build_regex(query)?;
+    let (scope_start, scope_end) = match query.scope {
+        Some((start, end)) => (char_to_byte(text, start), char_to_byte(text, end)),
+        None => (0, text.len()),
+    };
+    for m in re.find_iter(text) {
+        if m.start() < scope_start {
+            continue;
+        }
+        if m.end() > scope_end {
+            break;
+        }
+        if query.is_whole_word {
+            let before = text[..m.start()].chars().next_back();
+            let after = text[m.end()..].chars().next();
+            if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+                continue;
+            }
+        }
+        if !f(m.range()) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Finds every match in order and hands them to emit in batches, stopping early
+// when emit returns false or stop is set.
+pub fn find_matches(
+    text: &str,
+    query: &Query,
+    stop: &AtomicBool,
+    emit: &mut dyn FnMut(Vec<Match>) -> bool,
+) -> Result<(), regex::Error> {
+    let mut batch = Vec::new();
+    let mut tracker = PosTracker::new(text);
+    let mut is_stopped = false;
+    for_each_match(text, query, &mut |range| {
+        if stop.load(Ordering::Relaxed) {
+            is_stopped = true;
+            return false;
+        }
+        batch.push((tracker.pos(range.start), tracker.pos(range.end)));
+        if batch.len() >= BATCH_SIZE {
+            is_stopped = !emit(std::mem::take(&mut batch));
+        }
+        !is_stopped
+    })?;
+    if !batch.is_empty() && !is_stopped {
+        emit(batch);
+    }
+    Ok(())
+}
+
+// Search running on its own thread so typing in the prompt never waits for it.
+// Dropping the job stops the thread at its next match.
+pub struct SearchJob {
+    rx: Receiver<SearchMsg>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl SearchJob {
+    // The haystack is built from the snapshot on the search thread, without the
+    // trailing '\n' the buffer always keeps so a match can never remove it.
+    pub fn spawn(snapshot: Rope, query: Query) -> SearchJob {
+        let (tx, rx) = mpsc::channel();
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&is_stopped);
+        thread::spawn(move || {
+            if stop.load(Ordering::Relaxed) {
+                return;
+            }
+            let mut text = snapshot.to_string();
+            text.pop();
+            let res = find_matches(&text, &query, &stop, &mut |batch| {
+                tx.send(SearchMsg::Matches(batch)).is_ok()
+            });
+            if let Err(e) = res {
+                let _ = tx.send(SearchMsg::Error(regex_error_message(&e)));
+            }
+            let _ = tx.send(SearchMsg::Done);
+        });
+        SearchJob { rx, is_stopped }
+    }
+
+    pub fn poll(&self) -> Vec<SearchMsg> {
+        self.rx.try_iter().collect()
+    }
+}
+
+impl Drop for SearchJob {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+pub struct FileMatch {
+    // Relative to the root the search started from.
+    pub path: PathBuf,
+    // Line and column both start from 0.
+    pub line: usize,
+    pub col: usize,
+    pub snippet: String,
+}
+
+pub enum FileSearchMsg {
+    Matches(Vec<FileMatch>),
+    Error(String),
+    Done,
+}
+
+// Text of a file worth searching, None for binary or non UTF-8 files.
+fn read_text_file(path: &Path) -> Option<String> {
+    let bytes = fs::read(path).ok()?;
+    if bytes[..std::cmp::min(bytes.len(), BINARY_CHECK_BYTES)].contains(&0) {
+        return None;
+    }
+    let text = String::from_utf8(bytes).ok()?;
+    // Columns are counted the way the buffer sees the file, without the BOM.
+    Some(match text.strip_prefix('\u{FEFF}') {
+        Some(rest) => rest.to_owned(),
+        None => text,
+    })
+}
+
+fn file_matches(path: &Path, text: &str, query: &Query, limit: usize) -> Vec<FileMatch> {
+    let mut res = Vec::new();
+    let mut tracker = PosTracker::new(text);
+    let _ = for_each_match(text, query, &mut |range| {
+        let (col, line) = tracker.pos(range.start);
+        let line_start = tracker.line_start;
+        let line_end = text[line_start..]
+            .find('\n')
+            .map_or(text.len(), |i| line_start + i);
+        res.push(FileMatch {
+            path: path.to_path_buf(),
+            line,
+            col,
+            snippet: text[line_start..line_end].trim().to_owned(),
+        });
+        res.len() < limit
+    });
+    res
+}
+
+// Walks the tree under root on its own thread, skipping whatever .gitignore, hidden
+// file rules and the binary check exclude, and streams the matches of every file.
+// Dropping the job stops the walk at the next file.
+pub struct FileSearchJob {
+    rx: Receiver<FileSearchMsg>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl FileSearchJob {
+    pub fn spawn(root: PathBuf, query: Query) -> FileSearchJob {
+        let (tx, rx) = mpsc::channel();
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let stop = Arc::clone(&is_stopped);
+        thread::spawn(move || {
+            if let Err(e) = build_regex(&query) {
+                let _ = tx.send(FileSearchMsg::Error(regex_error_message(&e)));
+                let _ = tx.send(FileSearchMsg::Done);
+                return;
+            }
+            let mut found = 0;
+            for entry in WalkBuilder::new(&root).require_git(false).build() {
+                if stop.load(Ordering::Relaxed) || found >= MAX_FILE_MATCHES {
+                    break;
+                }
+                let Ok(entry) = entry else {
+                    continue;
+                };
+                if !entry.file_type().is_some_and(|t| t.is_file()) {
+                    continue;
+                }
+                let Some(text) = read_text_file(entry.path()) else {
+                    continue;
+                };
+                let path = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+                let matches = file_matches(path, &text, &query, MAX_FILE_MATCHES - found);
+                if matches.is_empty() {
+                    continue;
+                }
+                found += matches.len();
+                if tx.send(FileSearchMsg::Matches(matches)).is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send(FileSearchMsg::Done);
+        });
+        FileSearchJob { rx, is_stopped }
+    }
+
+    pub fn poll(&self) -> Vec<FileSearchMsg> {
+        self.rx.try_iter().collect()
+    }
+}
+
+impl Drop for FileSearchJob {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pattern: &str) -> Query {
+        Query {
+            pattern: pattern.to_owned(),
+            is_case_sensitive: true,
+            is_regex: false,
+            is_whole_word: false,
+            scope: None,
+        }
+    }
+
+    fn matches<'a>(text: &'a str, query: &Query) -> Vec<&'a str> {
+        let mut res = Vec::new();
+        for_each_match(text, query, &mut |range| {
+            res.push(&text[range]);
+            true
+        })
+        .unwrap();
+        res
+    }
+
+    #[test]
+    fn plain_text_is_escaped() {
+        let text = "a.b axb A.B";
+        assert_eq!(matches(text, &query("a.b")), ["a.b"]);
+        let q = Query {
+            is_case_sensitive: false,
+            ..query("a.b")
+        };
+        assert_eq!(matches(text, &q), ["a.b", "A.B"]);
+    }
+
+    #[test]
+    fn regex_with_line_anchors() {
+        let q = Query {
+            is_regex: true,
+            ..query(r"^\w+$")
+        };
+        assert_eq!(matches("one\ntwo three\nfour", &q), ["one", "four"]);
+        let q = Query {
+            is_regex: true,
+            ..query("(")
+        };
+        assert!(for_each_match("", &q, &mut |_| true).is_err());
+    }
+
+    #[test]
+    fn whole_word_checks_unicode_neighbours() {
+        let q = Query {
+            is_whole_word: true,
+            ..query("cat")
+        };
+        assert_eq!(matches("cat catalog écat cat_ (cat)", &q), ["cat", "cat"]);
+    }
+
+    #[test]
+    fn scope_is_in_chars() {
+        // "žž " is three chars but five bytes.
+        let text = "žž ab ab ab";
+        let q = Query {
+            scope: Some((3, 8)),
+            ..query("ab")
+        };
+        let mut ranges = Vec::new();
+        for_each_match(text, &q, &mut |r| {
+            ranges.push(r);
+            true
+        })
+        .unwrap();
+        assert_eq!(ranges, [5..7, 8..10]);
+        let q = Query {
+            scope: Some((3, 7)),
+            ..query("ab")
+        };
+        assert_eq!(matches(text, &q).len(), 1);
+    }
+
+    #[test]
+    fn empty_text_and_stopping_early() {
+        assert!(matches("", &query("a")).is_empty());
+        let mut count = 0;
+        for_each_match("a a a", &query("a"), &mut |_| {
+            count += 1;
+            count < 2
+        })
+        .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn positions_of_matches_on_the_last_line() {
+        let mut res = Vec::new();
+        find_matches(
+            "ß\nxß",
+            &query("ß"),
+            &AtomicBool::new(false),
+            &mut |batch| {
+                res.extend(batch);
+                true
+            },
+        )
+        .unwrap();
+        assert_eq!(res, [((0, 0), (1, 0)), ((1, 1), (2, 1))]);
+    }
+
+    #[test]
+    fn positions_on_a_long_multibyte_line() {
+        let text = "é,".repeat(3000);
+        let mut res = Vec::new();
+        find_matches(&text, &query(","), &AtomicBool::new(false), &mut |batch| {
+            res.extend(batch);
+            true
+        })
+        .unwrap();
+        assert_eq!(res.len(), 3000);
+        assert_eq!(res[2999], ((5999, 0), (6000, 0)));
+    }
+
+    #[test]
+    fn find_matches_stops_when_asked() {
+        let mut count = 0;
+        find_matches("a a a", &query("a"), &AtomicBool::new(true), &mut |batch| {
+            count += batch.len();
+            true
+        })
+        .unwrap();
+        assert_eq!(count, 0);
+    }
+}
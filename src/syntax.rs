@@ -0,0 +1,627 @@
+use std::{ops::Range, path::Path};
+
+use macroquad::prelude::{color_u8, Color};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    #[default]
+    Normal,
+    Keyword,
+    Type,
+    Function,
+    Macro,
+    Attribute,
+    String,
+    Number,
+    Comment,
+    Key,
+    Heading,
+    Emphasis,
+    Code,
+    Link,
+}
+
+pub const TOKEN_KINDS: usize = 14;
+
+// Colors indexed by TokenKind, Normal text is drawn with the font color instead.
+pub fn default_token_colors() -> [Color; TOKEN_KINDS] {
+    [
+        color_u8!(255, 255, 255, 255),
+        color_u8!(197, 134, 192, 255),
+        color_u8!(78, 201, 176, 255),
+        color_u8!(220, 220, 170, 255),
+        color_u8!(86, 156, 214, 255),
+        color_u8!(155, 155, 155, 255),
+        color_u8!(206, 145, 120, 255),
+        color_u8!(181, 206, 168, 255),
+        color_u8!(106, 153, 85, 255),
+        color_u8!(156, 220, 254, 255),
+        color_u8!(86, 156, 214, 255),
+        color_u8!(214, 157, 133, 255),
+        color_u8!(206, 145, 120, 255),
+        color_u8!(78, 148, 206, 255),
+    ]
+}
+
+// Where the tokenizer is at the end of a line, so the next line can continue a
+// comment or string that spans lines.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    #[default]
+    Normal,
+    // Nesting depth of /* */ comments.
+    BlockComment(u8),
+    Str,
+    // Number of '#' closing a raw string.
+    RawStr(u8),
+    // TOML """ or (literal) ''' string.
+    MultiStr(bool),
+    CodeFence,
+}
+
+type Tokenizer = fn(&[char], State, &mut [TokenKind]) -> State;
+
+#[derive(Debug)]
+pub struct Language {
+    pub name: &'static str,
+    extensions: &'static [&'static str],
+    tokenize: Tokenizer,
+}
+
+static LANGUAGES: [Language; 3] = [
+    Language {
+        name: "Rust",
+        extensions: &["rs"],
+        tokenize: tokenize_rust,
+    },
+    Language {
+        name: "TOML",
+        extensions: &["toml"],
+        tokenize: tokenize_toml,
+    },
+    Language {
+        name: "Markdown",
+        extensions: &["md", "markdown"],
+        tokenize: tokenize_markdown,
+    },
+];
+
+pub fn language_for(path: &Path) -> Option<&'static Language> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|l| l.extensions.contains(&ext.as_str()))
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type", "union",
+    "unsafe", "use", "where", "while",
+];
+
+const RUST_PRIMITIVES: &[&str] = &[
+    "bool", "char", "str", "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64",
+    "i128", "isize", "f32", "f64",
+];
+
+const TOML_KEYWORDS: &[&str] = &["true", "false", "inf", "nan"];
+
+fn mark(out: &mut [TokenKind], range: Range<usize>, kind: TokenKind) {
+    let end = std::cmp::min(range.end, out.len());
+    for k in out[std::cmp::min(range.start, end)..end].iter_mut() {
+        *k = kind;
+    }
+}
+
+fn starts_with(line: &[char], i: usize, pat: &str) -> bool {
+    pat.chars()
+        .enumerate()
+        .all(|(k, p)| line.get(i + k) == Some(&p))
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn scan_while(line: &[char], mut i: usize, f: impl Fn(char) -> bool) -> usize {
+    while i < line.len() && f(line[i]) {
+        i += 1;
+    }
+    i
+}
+
+// Index after the closing quote, or None when the line ends inside the string.
+fn scan_quoted(line: &[char], mut i: usize, quote: char, has_escapes: bool) -> Option<usize> {
+    while i < line.len() {
+        if has_escapes && line[i] == '\\' {
+            i += 2;
+            continue;
+        }
+        if line[i] == quote {
+            return Some(i + 1);
+        }
+        i += 1;
+    }
+    None
+}
+
+// Index after the comment closing the outermost level, or the remaining depth
+// when the line ends first.
+fn scan_block_comment(line: &[char], mut i: usize, mut depth: u8) -> Result<usize, u8> {
+    while i < line.len() {
+        if starts_with(line, i, "*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Ok(i);
+            }
+        } else if starts_with(line, i, "/*") {
+            depth = depth.saturating_add(1);
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+    Err(depth)
+}
+
+fn scan_number(line: &[char], i: usize) -> usize {
+    let mut j = i;
+    while j < line.len() {
+        let c = line[j];
+        // "1..2" is a range, not a float.
+        if c == '.' && line.get(j + 1).is_none_or(|n| !n.is_ascii_digit()) {
+            break;
+        }
+        if !(c.is_alphanumeric() || c == '_' || c == '.') {
+            break;
+        }
+        j += 1;
+    }
+    j
+}
+
+// Continues a token left open by the previous line, returns where normal
+// tokenizing resumes and the state after it.
+fn continue_open_token(line: &[char], state: State, out: &mut [TokenKind]) -> (usize, State) {
+    match state {
+        State::BlockComment(depth) => match scan_block_comment(line, 0, depth) {
+            Ok(end) => {
+                mark(out, 0..end, TokenKind::Comment);
+                (end, State::Normal)
+            }
+            Err(depth) => {
+                mark(out, 0..line.len(), TokenKind::Comment);
+                (line.len(), State::BlockComment(depth))
+            }
+        },
+        State::Str => match scan_quoted(line, 0, '"', true) {
+            Some(end) => {
+                mark(out, 0..end, TokenKind::String);
+                (end, State::Normal)
+            }
+            None => {
+                mark(out, 0..line.len(), TokenKind::String);
+                (line.len(), State::Str)
+            }
+        },
+        State::RawStr(hashes) => {
+            let closing = format!("\"{}", "#".repeat(hashes as usize));
+            match (0..line.len()).find(|&j| starts_with(line, j, &closing)) {
+                Some(j) => {
+                    let end = j + closing.chars().count();
+                    mark(out, 0..end, TokenKind::String);
+                    (end, State::Normal)
+                }
+                None => {
+                    mark(out, 0..line.len(), TokenKind::String);
+                    (line.len(), state)
+                }
+            }
+        }
+        State::MultiStr(is_literal) => {
+            let closing = if is_literal { "'''" } else { "\"\"\"" };
+            match (0..line.len()).find(|&j| starts_with(line, j, closing)) {
+                Some(j) => {
+                    mark(out, 0..j + 3, TokenKind::String);
+                    (j + 3, State::Normal)
+                }
+                None => {
+                    mark(out, 0..line.len(), TokenKind::String);
+                    (line.len(), state)
+                }
+            }
+        }
+        State::Normal | State::CodeFence => (0, state),
+    }
+}
+
+fn tokenize_rust(line: &[char], state: State, out: &mut [TokenKind]) -> State {
+    let (mut i, mut state) = continue_open_token(line, state, out);
+    while i < line.len() && state == State::Normal {
+        let c = line[i];
+        if starts_with(line, i, "//") {
+            mark(out, i..line.len(), TokenKind::Comment);
+            break;
+        }
+        if starts_with(line, i, "/*") {
+            match scan_block_comment(line, i + 2, 1) {
+                Ok(end) => {
+                    mark(out, i..end, TokenKind::Comment);
+                    i = end;
+                }
+                Err(depth) => {
+                    mark(out, i..line.len(), TokenKind::Comment);
+                    state = State::BlockComment(depth);
+                }
+            }
+            continue;
+        }
+        // r"..", r#".."#, br".." and b"..".
+        let prefix = if starts_with(line, i, "br") {
+            2
+        } else if c == 'r' || c == 'b' {
+            1
+        } else {
+            0
+        };
+        let is_raw = prefix > 0 && line[i + prefix - 1] == 'r';
+        let hashes = if is_raw {
+            scan_while(line, i + prefix, |c| c == '#') - (i + prefix)
+        } else {
+            0
+        };
+        let is_word_start = i == 0 || !is_ident_char(line[i - 1]);
+        if prefix > 0 && is_word_start && line.get(i + prefix + hashes) == Some(&'"') {
+            if is_raw {
+                let start = i;
+                i += prefix + hashes + 1;
+                let (end, next) =
+                    continue_open_token(&line[i..], State::RawStr(hashes as u8), &mut out[i..]);
+                mark(out, start..i, TokenKind::String);
+                i += end;
+                state = next;
+            } else {
+                mark(out, i..i + prefix, TokenKind::String);
+                i += prefix;
+            }
+            continue;
+        }
+        if c == '"' {
+            match scan_quoted(line, i + 1, '"', true) {
+                Some(end) => {
+                    mark(out, i..end, TokenKind::String);
+                    i = end;
+                }
+                None => {
+                    mark(out, i..line.len(), TokenKind::String);
+                    state = State::Str;
+                }
+            }
+            continue;
+        }
+        if c == '\'' {
+            // 'a' and '\n' are chars, 'a without a closing quote is a lifetime.
+            let end = if line.get(i + 1) == Some(&'\\') {
+                scan_quoted(line, i + 1, '\'', true)
+            } else if line.get(i + 2) == Some(&'\'') {
+                Some(i + 3)
+            } else {
+                None
+            };
+            match end {
+                Some(end) => {
+                    mark(out, i..end, TokenKind::String);
+                    i = end;
+                }
+                None => {
+                    let end = scan_while(line, i + 1, is_ident_char);
+                    mark(out, i..end, TokenKind::Type);
+                    i = std::cmp::max(end, i + 1);
+                }
+            }
+            continue;
+        }
+        if c.is_ascii_digit() && is_word_start {
+            let end = scan_number(line, i);
+            mark(out, i..end, TokenKind::Number);
+            i = end;
+            continue;
+        }
+        if c == '#' && (starts_with(line, i, "#[") || starts_with(line, i, "#![")) {
+            let mut depth = 0;
+            let mut end = line.len();
+            for (j, &ch) in line.iter().enumerate().skip(i) {
+                if ch == '[' {
+                    depth += 1;
+                } else if ch == ']' {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = j + 1;
+                        break;
+                    }
+                }
+            }
+            mark(out, i..end, TokenKind::Attribute);
+            i = end;
+            continue;
+        }
+        if is_ident_start(c) {
+            let end = scan_while(line, i, is_ident_char);
+            let word: String = line[i..end].iter().collect();
+            let next = scan_while(line, end, |c| c == ' ');
+            let kind = if RUST_KEYWORDS.contains(&word.as_str()) {
+                TokenKind::Keyword
+            } else if line.get(end) == Some(&'!') && line.get(end + 1) != Some(&'=') {
+                mark(out, end..end + 1, TokenKind::Macro);
+                TokenKind::Macro
+            } else if line.get(next) == Some(&'(') {
+                TokenKind::Function
+            } else if c.is_uppercase() || RUST_PRIMITIVES.contains(&word.as_str()) {
+                TokenKind::Type
+            } else {
+                TokenKind::Normal
+            };
+            mark(out, i..end, kind);
+            i = end;
+            continue;
+        }
+        i += 1;
+    }
+    state
+}
+
+fn tokenize_toml(line: &[char], state: State, out: &mut [TokenKind]) -> State {
+    let (mut i, mut state) = continue_open_token(line, state, out);
+    if state != State::Normal {
+        return state;
+    }
+    let first = scan_while(line, i, char::is_whitespace);
+    if i == 0 && line.get(first) == Some(&'[') {
+        let end = line
+            .iter()
+            .rposition(|&c| c == ']')
+            .map_or(line.len(), |j| j + 1);
+        mark(out, first..end, TokenKind::Heading);
+        i = end;
+    } else if i == 0 && line.get(first).is_some_and(|&c| c != '#') {
+        if let Some(eq) = line.iter().position(|&c| c == '=') {
+            let key_end = line[..eq]
+                .iter()
+                .rposition(|c| !c.is_whitespace())
+                .map_or(first, |j| j + 1);
+            mark(out, first..key_end, TokenKind::Key);
+            i = eq + 1;
+        }
+    }
+    while i < line.len() && state == State::Normal {
+        let c = line[i];
+        if c == '#' {
+            mark(out, i..line.len(), TokenKind::Comment);
+            break;
+        }
+        if starts_with(line, i, "\"\"\"") || starts_with(line, i, "'''") {
+            let is_literal = c == '\'';
+            let start = i;
+            i += 3;
+            let (end, next) =
+                continue_open_token(&line[i..], State::MultiStr(is_literal), &mut out[i..]);
+            mark(out, start..i, TokenKind::String);
+            i += end;
+            state = next;
+            continue;
+        }
+        if c == '"' || c == '\'' {
+            let end = scan_quoted(line, i + 1, c, c == '"').unwrap_or(line.len());
+            mark(out, i..end, TokenKind::String);
+            i = end;
+            continue;
+        }
+        let is_word_start = i == 0 || !is_ident_char(line[i - 1]);
+        if is_word_start
+            && (c.is_ascii_digit()
+                || ((c == '+' || c == '-') && line.get(i + 1).is_some_and(char::is_ascii_digit)))
+        {
+            // Also covers dates and times like 1979-05-27T07:32:00Z.
+            let end = scan_while(line, i + 1, |c| {
+                c.is_alphanumeric() || matches!(c, '_' | '.' | ':' | '-' | '+')
+            });
+            mark(out, i..end, TokenKind::Number);
+            i = end;
+            continue;
+        }
+        if is_ident_start(c) {
+            let end = scan_while(line, i, is_ident_char);
+            let word: String = line[i..end].iter().collect();
+            if TOML_KEYWORDS.contains(&word.as_str()) {
+                mark(out, i..end, TokenKind::Keyword);
+            }
+            i = end;
+            continue;
+        }
+        i += 1;
+    }
+    state
+}
+
+fn tokenize_markdown(line: &[char], state: State, out: &mut [TokenKind]) -> State {
+    let first = scan_while(line, 0, |c| c == ' ');
+    let is_fence = starts_with(line, first, "```") || starts_with(line, first, "~~~");
+    if state == State::CodeFence {
+        mark(out, 0..line.len(), TokenKind::Code);
+        return if is_fence {
+            State::Normal
+        } else {
+            State::CodeFence
+        };
+    }
+    if is_fence {
+        mark(out, 0..line.len(), TokenKind::Code);
+        return State::CodeFence;
+    }
+    if first <= 3 && line.get(first) == Some(&'#') {
+        mark(out, 0..line.len(), TokenKind::Heading);
+        return State::Normal;
+    }
+    if line.get(first) == Some(&'>') {
+        mark(out, 0..line.len(), TokenKind::Comment);
+        return State::Normal;
+    }
+    let mut i = first;
+    // List markers: "- ", "* ", "+ " and "1. ".
+    let digits = scan_while(line, first, |c| c.is_ascii_digit());
+    if matches!(line.get(first), Some('-' | '*' | '+')) && line.get(first + 1) == Some(&' ') {
+        mark(out, first..first + 1, TokenKind::Keyword);
+        i = first + 2;
+    } else if digits > first
+        && matches!(line.get(digits), Some('.' | ')'))
+        && line.get(digits + 1) == Some(&' ')
+    {
+        mark(out, first..digits + 1, TokenKind::Keyword);
+        i = digits + 2;
+    }
+    while i < line.len() {
+        let c = line[i];
+        if c == '`' {
+            let end = scan_quoted(line, i + 1, '`', false).unwrap_or(line.len());
+            mark(out, i..end, TokenKind::Code);
+            i = end;
+            continue;
+        }
+        if starts_with(line, i, "**") || starts_with(line, i, "__") {
+            let delim: String = line[i..i + 2].iter().collect();
+            if let Some(j) = (i + 2..line.len()).find(|&j| starts_with(line, j, &delim)) {
+                mark(out, i..j + 2, TokenKind::Emphasis);
+                i = j + 2;
+                continue;
+            }
+        }
+        if c == '*' && line.get(i + 1).is_some_and(|n| !n.is_whitespace()) {
+            if let Some(end) = scan_quoted(line, i + 1, '*', false) {
+                mark(out, i..end, TokenKind::Emphasis);
+                i = end;
+                continue;
+            }
+        }
+        if c == '[' {
+            if let Some(close) = scan_quoted(line, i + 1, ']', false) {
+                if line.get(close) == Some(&'(') {
+                    if let Some(end) = scan_quoted(line, close + 1, ')', false) {
+                        mark(out, i..end, TokenKind::Link);
+                        i = end;
+                        continue;
+                    }
+                }
+            }
+        }
+        i += 1;
+    }
+    State::Normal
+}
+
+#[derive(Debug, Default, Clone)]
+struct LineCache {
+    start: State,
+    end: State,
+    kinds: Vec<TokenKind>,
+    is_dirty: bool,
+}
+
+// Token kinds of every line, kept up to date lazily. An edit only marks the lines
+// it touched, the next lookup re-tokenizes from there and stops as soon as a line
+// starts in the same state it did before, so typing costs the same on any size
+// of document.
+#[derive(Debug, Default)]
+pub struct Highlighter {
+    language: Option<&'static Language>,
+    // One entry per '\n' plus the line after the last one.
+    lines: Vec<LineCache>,
+    // Every line before this one is up to date, stale ones after it are marked dirty.
+    first_dirty: usize,
+}
+
+fn dirty_line() -> LineCache {
+    LineCache {
+        is_dirty: true,
+        ..Default::default()
+    }
+}
+
+impl Highlighter {
+    pub fn new(language: Option<&'static Language>) -> Self {
+        Highlighter {
+            language,
+            ..Default::default()
+        }
+    }
+
+    pub fn language(&self) -> Option<&'static Language> {
+        self.language
+    }
+
+    pub fn reset(&mut self, newlines: usize) {
+        self.lines = vec![dirty_line(); newlines + 1];
+        self.first_dirty = 0;
+    }
+
+    // Starting inside the line, an edit replaced `removed` line breaks with `added` ones.
+    pub fn edit(&mut self, line: usize, removed: usize, added: usize) {
+        if self.language.is_none() {
+            return;
+        }
+        let end = std::cmp::min(line + removed + 1, self.lines.len());
+        let start = std::cmp::min(line, end);
+        self.lines
+            .splice(start..end, std::iter::repeat_n(dirty_line(), added + 1));
+        self.first_dirty = std::cmp::min(self.first_dirty, start);
+    }
+
+    // Kinds of the chars of the line, brought up to date through get_line first.
+    pub fn line_kinds(
+        &mut self,
+        line: usize,
+        get_line: impl Fn(usize) -> Vec<char>,
+    ) -> Option<&[TokenKind]> {
+        let language = self.language?;
+        if line >= self.lines.len() {
+            return None;
+        }
+        let mut i = self.first_dirty;
+        while i <= line {
+            let start = if i == 0 {
+                State::Normal
+            } else {
+                self.lines[i - 1].end
+            };
+            if !self.lines[i].is_dirty && self.lines[i].start == start {
+                // Nothing changed from here up to the next dirty line.
+                i = self.lines[i..]
+                    .iter()
+                    .position(|l| l.is_dirty)
+                    .map_or(self.lines.len(), |off| i + off);
+                continue;
+            }
+            let chars = get_line(i);
+            let mut kinds = vec![TokenKind::Normal; chars.len()];
+            let end = (language.tokenize)(&chars, start, &mut kinds);
+            self.lines[i] = LineCache {
+                start,
+                end,
+                kinds,
+                is_dirty: false,
+            };
+            i += 1;
+        }
+        // The line after the last one tokenized may now start in a different state.
+        if let Some(next) = self.lines.get_mut(i) {
+            next.is_dirty = true;
+        }
+        self.first_dirty = i;
+        Some(&self.lines[line].kinds)
+    }
+}
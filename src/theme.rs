@@ -0,0 +1,247 @@
+use std::path::PathBuf;
+
+use macroquad::prelude::{color_u8, Color};
+
+use crate::syntax::{default_token_colors, TokenKind, TOKEN_KINDS};
+
+// Compiled in so they are available without any themes directory.
+const BUILTIN_THEMES: [(&str, &str); 2] = [
+    ("dark", include_str!("../assets/themes/dark.toml")),
+    ("light", include_str!("../assets/themes/light.toml")),
+];
+
+const TOKEN_KEYS: [(&str, TokenKind); TOKEN_KINDS - 1] = [
+    ("keyword", TokenKind::Keyword),
+    ("type", TokenKind::Type),
+    ("function", TokenKind::Function),
+    ("macro", TokenKind::Macro),
+    ("attribute", TokenKind::Attribute),
+    ("string", TokenKind::String),
+    ("number", TokenKind::Number),
+    ("comment", TokenKind::Comment),
+    ("key", TokenKind::Key),
+    ("heading", TokenKind::Heading),
+    ("emphasis", TokenKind::Emphasis),
+    ("code", TokenKind::Code),
+    ("link", TokenKind::Link),
+];
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub name: String,
+    pub bg: Color,
+    pub font: Color,
+    pub cursor: Color,
+    pub selection: Color,
+    pub cursor_line: Color,
+    pub search: Color,
+    pub search_current: Color,
+    pub scrollbar: Color,
+    pub scrollbar_track: Color,
+    pub gutter: Color,
+    pub gutter_font: Color,
+    pub gutter_current: Color,
+    pub status_bg: Color,
+    pub status_fg: Color,
+    pub tab_bar: Color,
+    pub tab_bg: Color,
+    pub tab_fg: Color,
+    pub tab_active_bg: Color,
+    pub tab_active_fg: Color,
+    pub pane_border: Color,
+    pub pane_border_active: Color,
+    pub eof_bg: Color,
+    pub eof_fg: Color,
+    pub overlay_bg: Color,
+    pub overlay_fg: Color,
+    pub diff_added: Color,
+    pub diff_removed: Color,
+    // Indexed by TokenKind, Normal text uses the font color.
+    pub tokens: [Color; TOKEN_KINDS],
+}
+
+// The colors rustyed always had, also the base every theme file starts from.
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            name: "classic".to_owned(),
+            bg: color_u8!(0, 0, 0, 255),
+            font: color_u8!(255, 255, 255, 255),
+            cursor: color_u8!(200, 200, 200, 255),
+            selection: color_u8!(55, 95, 25, 5),
+            cursor_line: color_u8!(255, 255, 255, 10),
+            search: color_u8!(255, 255, 0, 70),
+            search_current: color_u8!(255, 140, 0, 150),
+            scrollbar: color_u8!(255, 255, 255, 40),
+            scrollbar_track: color_u8!(255, 255, 255, 15),
+            gutter: color_u8!(20, 20, 20, 255),
+            gutter_font: color_u8!(100, 100, 100, 255),
+            gutter_current: color_u8!(255, 255, 255, 255),
+            status_bg: color_u8!(255, 0, 0, 255),
+            status_fg: color_u8!(0, 0, 0, 255),
+            tab_bar: color_u8!(40, 40, 40, 255),
+            tab_bg: color_u8!(70, 70, 70, 255),
+            tab_fg: color_u8!(200, 200, 200, 255),
+            tab_active_bg: color_u8!(255, 0, 0, 255),
+            tab_active_fg: color_u8!(0, 0, 0, 255),
+            pane_border: color_u8!(70, 70, 70, 255),
+            pane_border_active: color_u8!(255, 0, 0, 255),
+            eof_bg: color_u8!(255, 255, 255, 100),
+            eof_fg: color_u8!(0, 0, 0, 255),
+            overlay_bg: color_u8!(0, 0, 0, 255),
+            overlay_fg: color_u8!(255, 255, 255, 255),
+            diff_added: color_u8!(0, 255, 0, 255),
+            diff_removed: color_u8!(255, 0, 0, 255),
+            tokens: default_token_colors(),
+        }
+    }
+}
+
+// "#rrggbb", "#rrggbbaa" or "r, g, b, a" like the config file uses.
+pub fn parse_color(s: &str) -> Option<Color> {
+    let s = s.trim();
+    let mut rgba = [255u8; 4];
+    if let Some(hex) = s.strip_prefix('#') {
+        if !hex.is_ascii() || (hex.len() != 6 && hex.len() != 8) {
+            return None;
+        }
+        for (i, c) in rgba.iter_mut().take(hex.len() / 2).enumerate() {
+            *c = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+    } else {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        if parts.len() != 3 && parts.len() != 4 {
+            return None;
+        }
+        for (c, p) in rgba.iter_mut().zip(parts) {
+            *c = p.parse().ok()?;
+        }
+    }
+    Some(Color::from_rgba(rgba[0], rgba[1], rgba[2], rgba[3]))
+}
+
+impl Theme {
+    fn ui_color(&mut self, key: &str) -> Option<&mut Color> {
+        Some(match key {
+            "bg" => &mut self.bg,
+            "font" => &mut self.font,
+            "cursor" => &mut self.cursor,
+            "selection" => &mut self.selection,
+            "cursor_line" => &mut self.cursor_line,
+            "search" => &mut self.search,
+            "search_current" => &mut self.search_current,
+            "scrollbar" => &mut self.scrollbar,
+            "scrollbar_track" => &mut self.scrollbar_track,
+            "gutter" => &mut self.gutter,
+            "gutter_font" => &mut self.gutter_font,
+            "gutter_current" => &mut self.gutter_current,
+            "status_bg" => &mut self.status_bg,
+            "status_fg" => &mut self.status_fg,
+            "tab_bar" => &mut self.tab_bar,
+            "tab_bg" => &mut self.tab_bg,
+            "tab_fg" => &mut self.tab_fg,
+            "tab_active_bg" => &mut self.tab_active_bg,
+            "tab_active_fg" => &mut self.tab_active_fg,
+            "pane_border" => &mut self.pane_border,
+            "pane_border_active" => &mut self.pane_border_active,
+            "eof_bg" => &mut self.eof_bg,
+            "eof_fg" => &mut self.eof_fg,
+            "overlay_bg" => &mut self.overlay_bg,
+            "overlay_fg" => &mut self.overlay_fg,
+            "diff_added" => &mut self.diff_added,
+            "diff_removed" => &mut self.diff_removed,
+            _ => return None,
+        })
+    }
+
+    fn token_color(&mut self, key: &str) -> Option<&mut Color> {
+        let (_, kind) = TOKEN_KEYS.iter().find(|(k, _)| *k == key)?;
+        Some(&mut self.tokens[*kind as usize])
+    }
+
+    // Colors missing from the file keep their classic value.
+    pub fn from_toml(name: &str, text: &str) -> Result<Theme, String> {
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e: toml::de::Error| format!("Theme {name}: {}", e.message()))?;
+        let mut theme = Theme {
+            name: name.to_owned(),
+            ..Default::default()
+        };
+        for section in ["ui", "syntax"] {
+            let Some(colors) = table.get(section).and_then(|v| v.as_table()) else {
+                continue;
+            };
+            for (key, value) in colors {
+                let color = value
+                    .as_str()
+                    .and_then(parse_color)
+                    .ok_or_else(|| format!("Theme {name}: invalid color for {section}.{key}"))?;
+                let slot = if section == "ui" {
+                    theme.ui_color(key)
+                } else {
+                    theme.token_color(key)
+                };
+                *slot.ok_or_else(|| format!("Theme {name}: unknown color {section}.{key}"))? =
+                    color;
+            }
+        }
+        Ok(theme)
+    }
+}
+
+// User themes are looked up in ./themes, then the config directory and on Linux
+// /etc/rustyed/themes.
+fn theme_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("themes")];
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+    if let Some(dir) = config_home {
+        dirs.push(dir.join("rustyed").join("themes"));
+    }
+    if cfg!(target_os = "linux") {
+        dirs.push(PathBuf::from("/etc/rustyed/themes"));
+    }
+    dirs
+}
+
+// A theme file with the name of a built-in theme replaces it.
+pub fn load_theme(name: &str) -> Result<Theme, String> {
+    for dir in theme_dirs() {
+        let path = dir.join(format!("{name}.toml"));
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            return Theme::from_toml(name, &text);
+        }
+    }
+    if let Some((_, text)) = BUILTIN_THEMES.iter().find(|(n, _)| *n == name) {
+        return Theme::from_toml(name, text);
+    }
+    if name == "classic" {
+        return Ok(Theme::default());
+    }
+    Err(format!("Theme {name} not found!"))
+}
+
+pub fn theme_names() -> Vec<String> {
+    let mut names: Vec<String> = std::iter::once("classic")
+        .chain(BUILTIN_THEMES.iter().map(|(n, _)| *n))
+        .map(str::to_owned)
+        .collect();
+    for dir in theme_dirs() {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == "toml") {
+                if let Some(stem) = path.file_stem() {
+                    names.push(stem.to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}